@@ -0,0 +1,59 @@
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::sync::Mutex;
+
+// First file descriptor passed by the service manager (SD_LISTEN_FDS_START)
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Listening sockets passed through `LISTEN_FDS`/`LISTEN_FDNAMES`, by name
+#[derive(Default)]
+pub struct ListenFds {
+    fds: Mutex<HashMap<String, VecDeque<OwnedFd>>>
+}
+
+impl ListenFds {
+    /// Take over the sockets and clear the variables.
+    /// Must run before any other thread is started, as changing the environment isn't thread safe
+    pub fn from_env() -> Self {
+        let mut fds: HashMap<String, VecDeque<OwnedFd>> = HashMap::new();
+
+        // The file descriptors are only meant for us when LISTEN_PID matches our own process
+        let pid = env::var("LISTEN_PID").ok().and_then(|x| x.parse::<u32>().ok());
+        let count = env::var("LISTEN_FDS").ok().and_then(|x| x.parse::<RawFd>().ok());
+        let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+
+        // Don't pass the sockets on to child processes
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+
+        let count = match (pid, count) {
+            (Some(pid), Some(count)) if pid == std::process::id() => count,
+            _ => return Self::default()
+        };
+
+        let mut names = names.split(':');
+        for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+            // SAFETY: the service manager hands over ownership of these file descriptors
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            if let Err(e) = fcntl(&fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+                eprintln!("Can't set close-on-exec on inherited socket: {}", e);
+            }
+
+            let name = names.next().filter(|x| !x.is_empty()).unwrap_or("unknown");
+            fds.entry(name.to_string()).or_default().push_back(fd);
+        }
+
+        Self {
+            fds: Mutex::new(fds)
+        }
+    }
+
+    /// Take an inherited listening socket passed with the given name
+    pub fn take(&self, name: &str) -> Option<OwnedFd> {
+        self.fds.lock().unwrap().get_mut(name).and_then(VecDeque::pop_front)
+    }
+}
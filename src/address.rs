@@ -0,0 +1,300 @@
+use aws_lc_rs::rand::{SecureRandom, SystemRandom};
+
+use hickory_resolver::config::{ConnectionConfig, NameServerConfig, ResolverConfig};
+use hickory_resolver::net::runtime::TokioRuntimeProvider;
+use hickory_resolver::proto::rr::rdata::SRV;
+use hickory_resolver::proto::rr::{Name, RData};
+use hickory_resolver::TokioResolver;
+
+use itertools::Itertools;
+
+use tokio::net::{TcpStream, UnixStream};
+use tokio::time::timeout;
+
+use std::fmt::{self, Display};
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::error::Error;
+use crate::io::ProxyStream;
+
+const DNS_PORT: u16 = 53;
+
+/// Target of a tunnel as configured, `host:port`, `unix:///path`, `unix://@name` for an abstract socket or `srv://name`
+#[derive(Debug)]
+pub struct Address {
+    target: String,
+    kind: Kind
+}
+
+#[derive(Debug)]
+enum Kind {
+    Tcp,
+    Unix(PathBuf),
+    Abstract(String),
+    Srv(Name, Box<TokioResolver>)
+}
+
+impl Address {
+    /// SRV records are resolved with `nameservers` when given, the system configuration otherwise
+    pub fn parse(target: &str, nameservers: Option<&[String]>) -> Result<Self, Error> {
+        let kind = if let Some(path) = target.strip_prefix("unix://") {
+            match path.strip_prefix('@') {
+                Some(name) => Kind::Abstract(name.to_string()),
+                None if path.starts_with('/') => Kind::Unix(PathBuf::from(path)),
+                None => return Err(format!("Unix socket target {} needs an absolute path", target).into())
+            }
+        } else if let Some(name) = target.strip_prefix("srv://") {
+            let name = Name::from_ascii(name).map_err(|e| format!("Invalid SRV target {}: {}", target, e))?;
+            Kind::Srv(name, Box::new(create_resolver(nameservers)?))
+        } else {
+            Kind::Tcp
+        };
+
+        Ok(Self {
+            target: target.to_string(),
+            kind
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.target
+    }
+
+    /// Connect within `duration` for each attempt, returning the stream and the host name to verify with TLS
+    pub async fn connect(&self, duration: Duration) -> io::Result<(ProxyStream, String)> {
+        match &self.kind {
+            Kind::Tcp => {
+                let host = self.target.rsplit_once(':').map_or(self.target.as_str(), |(host, _)| host).trim_matches(['[', ']']);
+                let stream = deadline(duration, TcpStream::connect(&self.target)).await?;
+                Ok((ProxyStream::new_tcp(stream), host.to_string()))
+            },
+            Kind::Unix(path) => Ok((ProxyStream::new_unix(deadline(duration, UnixStream::connect(path)).await?), "localhost".to_string())),
+            Kind::Abstract(name) => {
+                let addr = net::SocketAddr::from_abstract_name(name)?.into();
+                Ok((ProxyStream::new_unix(deadline(duration, UnixStream::connect_addr(&addr)).await?), "localhost".to_string()))
+            },
+            Kind::Srv(name, resolver) => connect_srv(name, resolver, duration).await
+        }
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.target)
+    }
+}
+
+fn create_resolver(nameservers: Option<&[String]>) -> Result<TokioResolver, Error> {
+    let builder = match nameservers {
+        Some(nameservers) => {
+            let nameservers = nameservers.iter().map(|x| {
+                let addr = x.parse::<SocketAddr>()
+                    .or_else(|_| x.parse().map(|ip| SocketAddr::new(ip, DNS_PORT)))
+                    .map_err(|_| format!("Invalid nameserver {}", x))?;
+                let connections = [ConnectionConfig::udp(), ConnectionConfig::tcp()].map(|mut connection| {
+                    connection.port = addr.port();
+                    connection
+                });
+                Ok::<_, Error>(NameServerConfig::new(addr.ip(), true, connections.into()))
+            }).collect::<Result<Vec<_>, _>>()?;
+            TokioResolver::builder_with_config(ResolverConfig::from_name_servers(nameservers), TokioRuntimeProvider::default())
+        },
+        None => TokioResolver::builder_tokio()?
+    };
+    Ok(builder.build()?)
+}
+
+/// Try the hosts of the SRV records in the order of RFC 2782
+async fn connect_srv(name: &Name, resolver: &TokioResolver, duration: Duration) -> io::Result<(ProxyStream, String)> {
+    let lookup = resolver.srv_lookup(name.clone()).await.map_err(io::Error::other)?;
+    let records = lookup.answers().iter()
+        .filter_map(|x| match &x.data {
+            RData::SRV(srv) => Some(srv.clone()),
+            _ => None
+        })
+        .collect::<Vec<_>>();
+
+    // A single record pointing at the root means the service is decidedly not available
+    if let [record] = records.as_slice() {
+        if record.target.is_root() {
+            return Err(io::Error::new(ErrorKind::NotFound, format!("service {} is not available", name)));
+        }
+    }
+
+    let mut error = None;
+    for record in order(records) {
+        let host = record.target.to_ascii();
+        let ips = match resolver.lookup_ip(record.target.clone()).await {
+            Ok(ips) => ips,
+            Err(e) => {
+                error = Some(io::Error::other(e));
+                continue;
+            }
+        };
+
+        for ip in ips.iter() {
+            match deadline(duration, TcpStream::connect((ip, record.port))).await {
+                Ok(stream) => return Ok((ProxyStream::new_tcp(stream), host.trim_end_matches('.').to_string())),
+                Err(e) => error = Some(e)
+            }
+        }
+    }
+
+    Err(error.unwrap_or_else(|| io::Error::new(ErrorKind::NotFound, format!("no SRV records for {}", name))))
+}
+
+/// Sort by priority, records of the same priority are picked at random in proportion to their weight
+fn order(mut records: Vec<SRV>) -> Vec<SRV> {
+    let random = SystemRandom::new();
+    records.sort_by_key(|x| x.priority);
+
+    let mut ordered = Vec::with_capacity(records.len());
+    for (_, group) in &records.into_iter().chunk_by(|x| x.priority) {
+        // Records of weight 0 go first, so they are only picked when the random number is 0
+        let mut group = group.sorted_by_key(|x| x.weight != 0).collect::<Vec<_>>();
+        while !group.is_empty() {
+            let total = group.iter().map(|x| u32::from(x.weight)).sum::<u32>();
+            let mut bytes = [0; 4];
+            let _ = random.fill(&mut bytes);
+            let pick = u32::from_ne_bytes(bytes) % (total + 1);
+
+            let mut sum = 0;
+            let index = group.iter()
+                .position(|x| {
+                    sum += u32::from(x.weight);
+                    sum >= pick
+                })
+                .unwrap_or_default();
+            ordered.push(group.remove(index));
+        }
+    }
+    ordered
+}
+
+async fn deadline<T>(duration: Duration, future: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    timeout(duration, future).await
+        .unwrap_or_else(|_| Err(io::Error::new(ErrorKind::TimedOut, "connection timed out")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hickory_resolver::proto::op::Message;
+    use hickory_resolver::proto::rr::{Record, RecordType};
+
+    use tokio::net::{TcpListener, UdpSocket};
+
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+
+    fn srv(priority: u16, weight: u16, target: &str) -> SRV {
+        SRV::new(priority, weight, 443, Name::from_ascii(target).unwrap())
+    }
+
+    fn targets(records: &[SRV]) -> Vec<String> {
+        records.iter().map(|x| x.target.to_ascii()).collect()
+    }
+
+    #[test]
+    fn order_by_priority() {
+        let records = vec![srv(3, 0, "c."), srv(1, 5, "a."), srv(2, 0, "b."), srv(1, 5, "a.")];
+        let ordered = order(records);
+        assert_eq!(ordered.iter().map(|x| x.priority).collect::<Vec<_>>(), vec![1, 1, 2, 3]);
+        assert_eq!(targets(&ordered), vec!["a.", "a.", "b.", "c."]);
+    }
+
+    #[test]
+    fn order_by_weight() {
+        let mut first = HashMap::new();
+        for _ in 0..1000 {
+            let ordered = order(vec![srv(1, 0, "zero."), srv(1, 1, "light."), srv(1, 98, "heavy."), srv(0, 0, "primary.")]);
+            assert_eq!(ordered.len(), 4);
+            assert_eq!(ordered[0].target.to_ascii(), "primary.");
+            *first.entry(ordered[1].target.to_ascii()).or_insert(0) += 1;
+        }
+
+        // Weights 0 and 1 each come first with a chance of 1 in 100
+        assert!(first.get("heavy.").is_some_and(|x| *x > 900));
+        assert!(first.get("zero.").is_none_or(|x| *x < 50));
+        assert!(first.get("light.").is_none_or(|x| *x < 80));
+    }
+
+    #[test]
+    fn order_only_weight_zero() {
+        let ordered = order(vec![srv(1, 0, "a."), srv(1, 0, "b.")]);
+        let mut targets = targets(&ordered);
+        targets.sort();
+        assert_eq!(targets, vec!["a.", "b."]);
+    }
+
+    /// Answers SRV queries with `records` and every A query with the loopback address
+    async fn nameserver(records: Vec<SRV>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (length, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let query = Message::from_vec(&buf[..length]).unwrap();
+                let mut response = Message::response(query.metadata.id, query.metadata.op_code);
+                response.metadata.recursion_desired = query.metadata.recursion_desired;
+                response.metadata.recursion_available = true;
+                for question in &query.queries {
+                    let name = question.name().clone();
+                    match question.query_type() {
+                        RecordType::SRV => response.add_answers(records.iter().map(|x| Record::from_rdata(name.clone(), 60, RData::SRV(x.clone())))),
+                        RecordType::A => response.add_answer(Record::from_rdata(name.clone(), 60, RData::from(Ipv4Addr::LOCALHOST))),
+                        _ => &mut response
+                    };
+                    response.add_query(question.clone());
+                }
+                socket.send_to(&response.to_vec().unwrap(), peer).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn connect_srv_fallback() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+        let open = listener.local_addr().unwrap().port();
+
+        let records = vec![
+            SRV::new(2, 0, open, Name::from_ascii("backup.example.test.").unwrap()),
+            SRV::new(1, 0, closed, Name::from_ascii("primary.example.test.").unwrap())
+        ];
+        let nameservers = [nameserver(records).await.to_string()];
+        let address = Address::parse("srv://_https._tcp.example.test.", Some(&nameservers)).unwrap();
+
+        // The primary refuses the connection, the backup is used
+        let (_, host) = address.connect(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(host, "backup.example.test");
+        listener.accept().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_srv_not_available() {
+        let nameservers = [nameserver(vec![SRV::new(0, 0, 0, Name::root())]).await.to_string()];
+        let address = Address::parse("srv://_https._tcp.example.test.", Some(&nameservers)).unwrap();
+        let result = address.connect(Duration::from_secs(5)).await;
+        assert!(result.is_err_and(|e| e.kind() == ErrorKind::NotFound));
+    }
+
+    #[tokio::test]
+    async fn connect_abstract() {
+        let name = format!("rproxy-test-{}", std::process::id());
+        let listener = tokio::net::UnixListener::bind_addr(&net::SocketAddr::from_abstract_name(&name).unwrap().into()).unwrap();
+        let address = Address::parse(&format!("unix://@{}", name), None).unwrap();
+        let (_, host) = address.connect(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(host, "localhost");
+        listener.accept().await.unwrap();
+    }
+}
@@ -0,0 +1,364 @@
+use aws_lc_rs::rand::{SecureRandom, SystemRandom};
+
+use fnv::FnvHasher;
+
+use futures::future::join_all;
+
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+use std::hash::Hasher;
+use std::io::{self, ErrorKind};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::address::Address;
+use crate::io::ProxyStream;
+use crate::settings::{self, Balance, Build};
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_FAILS: u32 = 1;
+const DEFAULT_FAIL_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_HEALTHY_THRESHOLD: u32 = 2;
+const DEFAULT_UNHEALTHY_THRESHOLD: u32 = 3;
+
+/// A backend with its open connections and health
+#[derive(Debug)]
+struct Target {
+    address: Address,
+    connections: AtomicUsize,
+    failures: AtomicU32,
+    // Milliseconds since the balancer started until which failed connections took the target out
+    down_until: AtomicU64,
+    // Result of the active health checks
+    healthy: AtomicBool
+}
+
+/// Picks the target of each connection, skipping targets found down by failed connections or health checks
+pub struct Balancer {
+    targets: Arc<Vec<Target>>,
+    balance: Balance,
+    connect_timeout: Duration,
+    max_fails: u32,
+    fail_timeout: Duration,
+    start: Instant,
+    next: AtomicUsize,
+    random: SystemRandom,
+    task: Option<JoinHandle<()>>
+}
+
+/// Counts a connection to a target until dropped
+pub struct Lease<'a> {
+    target: &'a Target
+}
+
+impl Target {
+    fn new(address: Address) -> Self {
+        Self {
+            address,
+            connections: AtomicUsize::new(0),
+            failures: AtomicU32::new(0),
+            down_until: AtomicU64::new(0),
+            healthy: AtomicBool::new(true)
+        }
+    }
+
+    fn available(&self, now: u64) -> bool {
+        self.healthy.load(Ordering::Relaxed) && self.down_until.load(Ordering::Relaxed) <= now
+    }
+}
+
+impl Balancer {
+    pub fn new(targets: Vec<Address>, settings: &settings::Tunnel, build: Build<'_>) -> Self {
+        let targets = Arc::new(targets.into_iter().map(Target::new).collect::<Vec<_>>());
+        let task = settings.health_check.as_ref().and_then(|x| build.spawn(health_check(targets.clone(), HealthCheck::new(x))));
+
+        Self {
+            targets,
+            balance: settings.balance.unwrap_or(Balance::RoundRobin),
+            connect_timeout: settings.connect_timeout.map(Duration::from_millis).unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+            max_fails: settings.max_fails.unwrap_or(DEFAULT_MAX_FAILS),
+            fail_timeout: settings.fail_timeout.map(Duration::from_millis).unwrap_or(DEFAULT_FAIL_TIMEOUT),
+            start: Instant::now(),
+            next: AtomicUsize::new(0),
+            random: SystemRandom::new(),
+            task
+        }
+    }
+
+    /// Connect to the selected target, trying the others when it fails. The host name to verify with TLS is returned with the stream
+    pub async fn connect(&self, client: Option<IpAddr>) -> io::Result<(ProxyStream, String, Lease<'_>)> {
+        let now = self.elapsed();
+        let mut candidates = self.targets.iter().filter(|x| x.available(now)).collect::<Vec<_>>();
+        // Without any target known to be up all of them are tried
+        if candidates.is_empty() {
+            candidates = self.targets.iter().collect();
+        }
+
+        let mut error = None;
+        while !candidates.is_empty() {
+            let target = candidates.remove(self.select(&candidates, client));
+            match target.address.connect(self.connect_timeout).await {
+                Ok((stream, host)) => {
+                    target.failures.store(0, Ordering::Relaxed);
+                    target.connections.fetch_add(1, Ordering::Relaxed);
+                    return Ok((stream, host, Lease { target }));
+                },
+                Err(e) => {
+                    println!("Failed to connect to {}: {}", target.address, e);
+                    self.fail(target);
+                    error = Some(e);
+                }
+            }
+        }
+
+        Err(error.unwrap_or_else(|| io::Error::new(ErrorKind::NotFound, "no tunnel targets")))
+    }
+
+    fn select(&self, candidates: &[&Target], client: Option<IpAddr>) -> usize {
+        let count = candidates.len();
+        match (self.balance, client) {
+            (Balance::LeastConnections, _) => {
+                // Ties are spread by starting at a rotating offset
+                let offset = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..count)
+                    .map(|i| (offset + i) % count)
+                    .min_by_key(|&i| candidates[i].connections.load(Ordering::Relaxed))
+                    .unwrap_or_default()
+            },
+            (Balance::RandomTwo, _) if count > 1 => {
+                let first = self.random(count);
+                let second = (first + 1 + self.random(count - 1)) % count;
+                match candidates[second].connections.load(Ordering::Relaxed) < candidates[first].connections.load(Ordering::Relaxed) {
+                    true => second,
+                    false => first
+                }
+            },
+            // Rendezvous hashing only moves the clients of a target which goes down
+            (Balance::IpHash, Some(client)) => (0..count)
+                .max_by_key(|&i| rendezvous_hash(client, candidates[i].address.as_str()))
+                .unwrap_or_default(),
+            _ => self.next.fetch_add(1, Ordering::Relaxed) % count
+        }
+    }
+
+    fn fail(&self, target: &Target) {
+        if self.max_fails > 0 && target.failures.fetch_add(1, Ordering::Relaxed) + 1 >= self.max_fails {
+            target.failures.store(0, Ordering::Relaxed);
+            target.down_until.store(self.elapsed() + self.fail_timeout.as_millis() as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn elapsed(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn random(&self, count: usize) -> usize {
+        let mut bytes = [0; 8];
+        let _ = self.random.fill(&mut bytes);
+        (u64::from_ne_bytes(bytes) % count as u64) as usize
+    }
+}
+
+/// Weight of a target for a client, a fixed hash keeps the choice stable across restarts and instances
+fn rendezvous_hash(client: IpAddr, target: &str) -> u64 {
+    let mut hasher = FnvHasher::default();
+    match client {
+        IpAddr::V4(ip) => hasher.write(&ip.octets()),
+        IpAddr::V6(ip) => hasher.write(&ip.octets())
+    }
+    hasher.write(target.as_bytes());
+    hasher.finish()
+}
+
+impl Drop for Balancer {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        self.target.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct HealthCheck {
+    interval: Duration,
+    timeout: Duration,
+    healthy_threshold: u32,
+    unhealthy_threshold: u32
+}
+
+impl HealthCheck {
+    fn new(settings: &settings::HealthCheck) -> Self {
+        Self {
+            interval: settings.interval.map(Duration::from_millis).unwrap_or(DEFAULT_INTERVAL),
+            timeout: settings.timeout.map(Duration::from_millis).unwrap_or(DEFAULT_CHECK_TIMEOUT),
+            healthy_threshold: settings.healthy_threshold.unwrap_or(DEFAULT_HEALTHY_THRESHOLD),
+            unhealthy_threshold: settings.unhealthy_threshold.unwrap_or(DEFAULT_UNHEALTHY_THRESHOLD)
+        }
+    }
+}
+
+/// Connect to every target each interval, a target changes state after enough consecutive results
+async fn health_check(targets: Arc<Vec<Target>>, settings: HealthCheck) {
+    // Consecutive results which disagree with the current state of each target
+    let mut streaks = vec![0; targets.len()];
+    loop {
+        let results = join_all(targets.iter().map(|x| x.address.connect(settings.timeout))).await;
+        for ((target, result), streak) in targets.iter().zip(results).zip(&mut streaks) {
+            let up = result.is_ok();
+            if up == target.healthy.load(Ordering::Relaxed) {
+                *streak = 0;
+                continue;
+            }
+
+            *streak += 1;
+            let threshold = match up {
+                true => settings.healthy_threshold,
+                false => settings.unhealthy_threshold
+            };
+            if *streak >= threshold {
+                *streak = 0;
+                target.healthy.store(up, Ordering::Relaxed);
+                println!("Tunnel target {} is {}", target.address, if up { "up" } else { "down" });
+            }
+        }
+
+        sleep(settings.interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use crate::tls::sessions::Sessions;
+
+    use super::*;
+
+    fn balancer(balance: Balance, targets: &[&str], max_fails: Option<u32>) -> Balancer {
+        let settings = settings::Tunnel {
+            target: None,
+            targets: None,
+            balance: Some(balance),
+            connect_timeout: Some(1000),
+            max_fails,
+            fail_timeout: None,
+            health_check: None,
+            nameservers: None,
+            proxy_protocol: None,
+            tls: None
+        };
+        let targets = targets.iter().map(|x| Address::parse(x, None).unwrap()).collect();
+        Balancer::new(targets, &settings, Build::Check(&Sessions::new(None, None).unwrap()))
+    }
+
+    fn candidates(balancer: &Balancer) -> Vec<&Target> {
+        balancer.targets.iter().collect()
+    }
+
+    #[test]
+    fn round_robin() {
+        let balancer = balancer(Balance::RoundRobin, &["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"], None);
+        let picks = (0..4).map(|_| balancer.select(&candidates(&balancer), None)).collect::<Vec<_>>();
+        assert_eq!(picks, [0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn least_connections() {
+        let balancer = balancer(Balance::LeastConnections, &["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"], None);
+        for (target, connections) in balancer.targets.iter().zip([3, 1, 2]) {
+            target.connections.store(connections, Ordering::Relaxed);
+        }
+        for _ in 0..3 {
+            assert_eq!(balancer.select(&candidates(&balancer), None), 1);
+        }
+
+        // Ties rotate instead of always landing on the first target
+        balancer.targets[1].connections.store(3, Ordering::Relaxed);
+        balancer.targets[2].connections.store(3, Ordering::Relaxed);
+        let picks = (0..3).map(|_| balancer.select(&candidates(&balancer), None)).collect::<Vec<_>>();
+        assert_eq!(picks.iter().filter(|&&x| x == picks[0]).count(), 1);
+    }
+
+    #[test]
+    fn random_two() {
+        // With two targets both are sampled, so the less loaded one always wins
+        let balancer = balancer(Balance::RandomTwo, &["127.0.0.1:1", "127.0.0.1:2"], None);
+        balancer.targets[0].connections.store(5, Ordering::Relaxed);
+        for _ in 0..20 {
+            assert_eq!(balancer.select(&candidates(&balancer), None), 1);
+        }
+    }
+
+    #[test]
+    fn ip_hash() {
+        let targets = ["10.0.0.1:443", "10.0.0.2:443", "10.0.0.3:443"];
+        let balancer = balancer(Balance::IpHash, &targets, None);
+        let all = candidates(&balancer);
+
+        // The hash is fixed, so clients land on the same target after a restart or on another instance
+        assert_eq!(rendezvous_hash("192.0.2.1".parse().unwrap(), "10.0.0.1:443"), 0xa97c26ec2f34964f);
+
+        let mut counts = [0; 3];
+        for i in 0..=255 {
+            let client = IpAddr::from([198, 51, 100, i]);
+            let selected = balancer.select(&all, Some(client));
+            assert_eq!(balancer.select(&all, Some(client)), selected);
+            counts[selected] += 1;
+
+            // Taking out another target doesn't move the client
+            let other = (selected + 1) % 3;
+            let remaining = all.iter().enumerate().filter(|(i, _)| *i != other).map(|(_, x)| *x).collect::<Vec<_>>();
+            assert_eq!(remaining[balancer.select(&remaining, Some(client))].address.as_str(), targets[selected]);
+        }
+        assert!(counts.iter().all(|&x| x > 40), "{:?}", counts);
+    }
+
+    #[test]
+    fn failure_threshold() {
+        let passive = balancer(Balance::RoundRobin, &["127.0.0.1:1"], Some(2));
+        let target = &passive.targets[0];
+
+        passive.fail(target);
+        assert!(target.available(passive.elapsed()));
+        passive.fail(target);
+        assert!(!target.available(passive.elapsed()));
+        assert_eq!(target.failures.load(Ordering::Relaxed), 0);
+
+        // Passive checks are off with max_fails 0
+        let disabled = balancer(Balance::RoundRobin, &["127.0.0.1:1"], Some(0));
+        for _ in 0..5 {
+            disabled.fail(&disabled.targets[0]);
+        }
+        assert!(disabled.targets[0].available(disabled.elapsed()));
+    }
+
+    #[tokio::test]
+    async fn failed_connection_marks_target() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().to_string();
+        let open = listener.local_addr().unwrap().to_string();
+        let balancer = balancer(Balance::RoundRobin, &[&closed, &open], None);
+
+        let (_, _, lease) = balancer.connect(None).await.unwrap();
+        assert!(!balancer.targets[0].available(balancer.elapsed()));
+        assert_eq!(balancer.targets[1].connections.load(Ordering::Relaxed), 1);
+        drop(lease);
+        assert_eq!(balancer.targets[1].connections.load(Ordering::Relaxed), 0);
+
+        // The failed target is skipped until fail_timeout passed
+        for _ in 0..2 {
+            let (_, _, lease) = balancer.connect(None).await.unwrap();
+            assert_eq!(lease.target.address.as_str(), open);
+        }
+    }
+}
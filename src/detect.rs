@@ -0,0 +1,183 @@
+use async_trait::async_trait;
+
+use tokio::io::AsyncReadExt;
+use tokio::time::timeout;
+
+use std::error::Error;
+use std::time::Duration;
+
+use crate::handler::{Context, Handler, SendableHandler};
+use crate::io::ProxyStream;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Tls,
+    Http,
+    Http2,
+    Ssh,
+    Proxy
+}
+
+const SIGNATURES: &[(Protocol, &[u8])] = &[
+    (Protocol::Tls, &[0x16, 0x03]),
+    (Protocol::Http2, b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"),
+    (Protocol::Ssh, b"SSH-"),
+    (Protocol::Proxy, b"PROXY "),
+    (Protocol::Proxy, b"\r\n\r\n\0\r\nQUIT\n"),
+    (Protocol::Http, b"GET "),
+    (Protocol::Http, b"HEAD "),
+    (Protocol::Http, b"POST "),
+    (Protocol::Http, b"PUT "),
+    (Protocol::Http, b"DELETE "),
+    (Protocol::Http, b"OPTIONS "),
+    (Protocol::Http, b"PATCH "),
+    (Protocol::Http, b"CONNECT "),
+    (Protocol::Http, b"TRACE ")
+];
+
+/// Dispatches a connection by recognizing the protocol from its first bytes
+pub struct DetectHandler {
+    routes: Vec<(Protocol, SendableHandler)>,
+    fallback: Option<SendableHandler>,
+    timeout: Duration
+}
+
+impl DetectHandler {
+    pub fn new(routes: Vec<(Protocol, SendableHandler)>, fallback: Option<SendableHandler>, timeout: Option<u64>) -> Self {
+        Self {
+            routes,
+            fallback,
+            timeout: timeout.map(Duration::from_millis).unwrap_or(DEFAULT_TIMEOUT)
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for DetectHandler {
+    async fn handle(&self, mut stream: ProxyStream, ctx: Context) -> Result<(), Box<dyn Error>> {
+        let mut buf = Vec::new();
+        // Server speaks first protocols never send anything, so they end up at the fallback after the timeout
+        let protocol = timeout(self.timeout, async {
+            loop {
+                match detect(&buf) {
+                    Detected::Protocol(protocol) => return Ok(Some(protocol)),
+                    Detected::Unknown => return Ok(None),
+                    Detected::NeedMore => {}
+                }
+
+                if stream.read_buf(&mut buf).await? == 0 {
+                    return Ok::<_, std::io::Error>(None);
+                }
+            }
+        }).await.unwrap_or(Ok(None))?;
+
+        if buf.is_empty() && protocol.is_none() && self.fallback.is_none() {
+            return Ok(());
+        }
+
+        let handler = self.routes.iter()
+            .find(|(x, _)| Some(*x) == protocol)
+            .map(|(_, handler)| handler)
+            .or(self.fallback.as_ref())
+            .ok_or("Unrecognized protocol and no fallback handler")?;
+
+        handler.handle(ProxyStream::new_prefixed(buf, stream), ctx).await
+    }
+
+    /// Union of the protocols of the routes and the fallback, so a TLS handler in front can advertise them
+    fn alpn_protocols(&self) -> Option<Vec<String>> {
+        let mut protocols: Vec<String> = Vec::new();
+        let handlers = self.routes.iter().map(|(_, x)| x).chain(self.fallback.as_ref());
+        for protocol in handlers.flat_map(|x| x.alpn_protocols().unwrap_or_default()) {
+            if !protocols.contains(&protocol) {
+                protocols.push(protocol);
+            }
+        }
+
+        Some(protocols).filter(|x| !x.is_empty())
+    }
+}
+
+enum Detected {
+    Protocol(Protocol),
+    Unknown,
+    NeedMore
+}
+
+fn detect(buf: &[u8]) -> Detected {
+    let mut need_more = false;
+    for (protocol, signature) in SIGNATURES {
+        if buf.starts_with(signature) {
+            return Detected::Protocol(*protocol);
+        }
+        need_more |= signature.starts_with(buf);
+    }
+
+    match need_more {
+        true => Detected::NeedMore,
+        false => Detected::Unknown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Advertising(&'static [&'static str]);
+
+    #[async_trait]
+    impl Handler for Advertising {
+        async fn handle(&self, _stream: ProxyStream, _ctx: Context) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+
+        fn alpn_protocols(&self) -> Option<Vec<String>> {
+            Some(self.0.iter().map(|x| x.to_string()).collect())
+        }
+    }
+
+    fn detected(buf: &[u8]) -> Option<Protocol> {
+        match detect(buf) {
+            Detected::Protocol(protocol) => Some(protocol),
+            Detected::Unknown => None,
+            Detected::NeedMore => panic!("more bytes needed for {:?}", buf)
+        }
+    }
+
+    #[test]
+    fn protocols() {
+        assert!(detected(&[0x16, 0x03, 0x01, 0x02, 0x00]) == Some(Protocol::Tls));
+        assert!(detected(b"GET / HTTP/1.1\r\n") == Some(Protocol::Http));
+        assert!(detected(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n") == Some(Protocol::Http2));
+        assert!(detected(b"SSH-2.0-OpenSSH_9.6\r\n") == Some(Protocol::Ssh));
+        assert!(detected(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n") == Some(Protocol::Proxy));
+        assert!(detected(b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c") == Some(Protocol::Proxy));
+    }
+
+    #[test]
+    fn partial_prefixes() {
+        for buf in [&b""[..], &[0x16], b"G", b"GE", b"PR", b"PROXY", b"PRI * HTTP/2", b"\r\n\r\n\0", b"SSH"] {
+            assert!(matches!(detect(buf), Detected::NeedMore), "{:?}", buf);
+        }
+    }
+
+    #[test]
+    fn unknown() {
+        for buf in [&b"\x16\x02"[..], b"get / HTTP/1.1", b"GETX", b"EHLO example.com\r\n", b"\r\n\r\n\0\r\nQUIT\r", b"PROXY\r\n"] {
+            assert!(detected(buf).is_none(), "{:?}", buf);
+        }
+    }
+
+    #[test]
+    fn advertised_union() {
+        let handler = DetectHandler::new(vec![
+            (Protocol::Http2, Box::new(Advertising(&["h2"]))),
+            (Protocol::Http, Box::new(Advertising(&["http/1.1", "h2"])))
+        ], Some(Box::new(Advertising(&["imap"]))), None);
+        assert_eq!(handler.alpn_protocols().unwrap(), ["h2", "http/1.1", "imap"]);
+
+        assert_eq!(DetectHandler::new(vec![], None, None).alpn_protocols(), None);
+    }
+}
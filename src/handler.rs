@@ -1,16 +1,26 @@
 use async_trait::async_trait;
 
 use std::error::Error;
-use std::net::IpAddr;
+use std::net::SocketAddr;
 
 use crate::io::ProxyStream;
+use crate::settings::StartTlsProtocol;
+use crate::shutdown::Shutdown;
+use crate::tls::{ClientCertificate, TlsFingerprint};
 
 #[derive(Default, Clone)]
 pub struct Context {
     pub secure: bool,
-    pub addr: Option<IpAddr>,
+    pub addr: Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
     pub alpn: Option<String>,
-    pub server_name: Option<String>
+    pub server_name: Option<String>,
+    pub tls_version: Option<String>,
+    pub cipher: Option<String>,
+    pub client_certificate: Option<ClientCertificate>,
+    pub tls_fingerprint: Option<TlsFingerprint>,
+    pub starttls: Option<StartTlsProtocol>,
+    pub shutdown: Shutdown
 }
 
 #[async_trait]
@@ -14,7 +14,7 @@ use cookie::Cookie;
 
 use hyper::body::{Bytes, Incoming};
 use hyper::header::HeaderValue;
-use hyper::http::uri::{Authority, InvalidUri, Scheme};
+use hyper::http::uri::{Authority, Scheme};
 use hyper::{header, Request, Response, StatusCode, Uri};
 
 use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
@@ -52,12 +52,8 @@ pub struct AuthenticatorService {
     pub session_cookie: String
 }
 
-#[derive(Deserialize)]
-pub struct Token {}
-
 #[derive(Debug)]
 pub enum ClientError {
-    Uri(InvalidUri),
     Other(&'static str)
 }
 
@@ -65,7 +61,9 @@ impl error::Error for ClientError {}
 
 impl Display for ClientError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str("Upstream server error")
+        match self {
+            ClientError::Other(e) => write!(f, "Upstream server error: {}", e)
+        }
     }
 }
 
@@ -172,6 +170,12 @@ impl AuthenticatorService {
             session_cookie: "session".to_string()
         })
     }
+
+    /// Validate the settings without creating the HTTP client, the provider is only contacted on the first login
+    pub fn check(discovery_url: &str) -> Result<(), Error> {
+        IssuerUrl::new(discovery_url.to_string())?;
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -182,9 +186,8 @@ impl HttpService for AuthenticatorService {
 
         let session_cookies: Vec<Cookie> = req.headers().get_all(header::COOKIE).iter()
             .filter_map(|c| c.to_str().ok())
-            .map(|c| Cookie::split_parse(c)
-                .filter_map(|c| c.ok().filter(|c| c.name() == self.session_cookie)))
-            .flatten().collect();
+            .flat_map(|c| Cookie::split_parse(c)
+                .filter_map(|c| c.ok().filter(|c| c.name() == self.session_cookie))).collect();
 
         let mut session = None;
         for session_cookie in session_cookies {
@@ -266,8 +269,8 @@ impl HttpService for AuthenticatorService {
         let (redirect, state, nonce) = client
             .authorize_url(
                 CoreAuthenticationFlow::AuthorizationCode,
-                || CsrfToken::new_random(),
-                || Nonce::new_random(),
+                CsrfToken::new_random,
+                Nonce::new_random,
             )
             .add_scope(Scope::new("email".to_string()))
             .url();
@@ -0,0 +1,53 @@
+use async_trait::async_trait;
+
+use hyper::body::{Bytes, Incoming};
+use hyper::{Request, Response, StatusCode};
+
+use http_body_util::{combinators::BoxBody, BodyExt, Empty};
+
+use std::sync::Arc;
+
+use crate::handler::Context;
+use crate::settings;
+use crate::tls::ClientCertificate;
+
+use super::{HttpError, HttpService};
+
+/// Only passes requests made with a client certificate matching one of the allowed values
+pub struct ClientCertificateLayer {
+    service: Arc<dyn HttpService + Send + Sync>,
+    subjects: Vec<String>,
+    sans: Vec<String>,
+    fingerprints: Vec<String>
+}
+
+impl ClientCertificateLayer {
+    pub fn new(service: Arc<dyn HttpService + Send + Sync>, settings: &settings::ClientCertificate) -> Self {
+        Self {
+            service,
+            subjects: settings.subjects.clone().unwrap_or_default(),
+            sans: settings.sans.clone().unwrap_or_default(),
+            fingerprints: settings.fingerprints.iter().flatten().map(|x| x.replace(':', "").to_lowercase()).collect()
+        }
+    }
+
+    fn allowed(&self, certificate: &ClientCertificate) -> bool {
+        self.subjects.contains(&certificate.subject)
+            || self.fingerprints.contains(&certificate.fingerprint)
+            || certificate.sans.iter().any(|x| self.sans.contains(x))
+    }
+}
+
+#[async_trait]
+impl HttpService for ClientCertificateLayer {
+    async fn call(&self, req: Request<Incoming>) -> Result<Response<BoxBody<Bytes, HttpError>>, HttpError> {
+        let ctx = req.extensions().get::<Context>().unwrap();
+        if !ctx.client_certificate.as_ref().is_some_and(|x| self.allowed(x)) {
+            return Ok(Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(BoxBody::new(Empty::new().map_err(From::from)))?);
+        }
+
+        self.service.call(req).await
+    }
+}
@@ -11,13 +11,20 @@ use hyper_util::rt::{TokioExecutor, TokioIo};
 
 use http_body_util::combinators::BoxBody;
 
+use rustls_platform_verifier::ConfigVerifierExt;
+
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpStream, UnixStream};
 use tokio::sync::Mutex;
 
 use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::ClientConfig;
 use tokio_rustls::TlsConnector;
 
+use crate::handler::Context;
 use crate::io::AsyncStream;
+use crate::proxy_protocol::ProxyHeader;
+use crate::settings::ProxyProtocolVersion;
 
 use super::HttpError;
 
@@ -27,27 +34,40 @@ pub enum Connection {
     None,
 }
 
-type Pool = Arc<Mutex<HashMap<Uri, VecDeque<Connection>>>>;
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct PoolKey {
+    uri: Uri,
+    header: Option<Vec<u8>>,
+}
+
+type Pool = Arc<Mutex<HashMap<PoolKey, VecDeque<Connection>>>>;
+
+/// Upstream connections opened on behalf of one client connection, they are closed when it ends
+#[derive(Clone, Default)]
+pub struct ClientConnections(Pool);
 
 pub struct Reservation {
     pub conn: Connection,
-    uri: Uri,
-    pool: Pool,
+    key: PoolKey,
+    pool: Option<Pool>,
 }
 
 pub struct Client {
     connector: TlsConnector,
     pool: Pool,
+    proxy_protocol: Option<ProxyProtocolVersion>,
 }
 
 impl Drop for Reservation {
     fn drop(&mut self) {
-        let pool = self.pool.clone();
-        let uri = self.uri.clone();
+        let Some(pool) = self.pool.clone() else {
+            return;
+        };
+        let key = self.key.clone();
         let mut conn = Connection::None;
         mem::swap(&mut conn, &mut self.conn);
         tokio::spawn(async move {
-            pool.lock().await.entry(uri).or_default().push_back(conn);
+            pool.lock().await.entry(key).or_default().push_back(conn);
         });
     }
 }
@@ -62,6 +82,14 @@ impl Reservation {
 }
 
 impl Connection {
+    fn is_open(&self) -> bool {
+        match self {
+            Connection::Http1(x) => x.is_ready() && !x.is_closed(),
+            Connection::Http2(x) => x.is_ready() && !x.is_closed(),
+            Connection::None => unreachable!(),
+        }
+    }
+
     pub async fn send_request(
         &mut self,
         req: Request<BoxBody<Bytes, HttpError>>,
@@ -76,65 +104,108 @@ impl Connection {
 
 impl Client {
     pub fn new() -> Self {
-        let mut config = rustls_platform_verifier::tls_config();
+        Self::with_proxy_protocol(None)
+    }
+
+    pub fn with_proxy_protocol(version: Option<ProxyProtocolVersion>) -> Self {
+        let mut config = ClientConfig::with_platform_verifier();
         config.alpn_protocols = vec!["h2".into(), "http/1.1".into()];
 
         Self {
             connector: TlsConnector::from(Arc::new(config)),
             pool: Arc::new(Mutex::new(HashMap::new())),
+            proxy_protocol: version,
         }
     }
 
     pub async fn get_connection(&self, uri: &Uri) -> Result<Reservation, HttpError> {
-        let mut pool = self.pool.lock().await;
-        if let Some(pool) = pool.get_mut(uri) {
+        self.get_pooled_connection(PoolKey {
+            uri: uri.clone(),
+            header: None,
+        }, &self.pool).await
+    }
+
+    /// Get a connection on behalf of the client in `ctx`, announcing it with a PROXY protocol header when enabled.
+    /// Such a connection can't be shared with other clients, so it is only reused by the same client connection
+    pub async fn get_connection_for(&self, uri: &Uri, ctx: Option<&Context>, connections: Option<&ClientConnections>) -> Result<Reservation, HttpError> {
+        let Some(version) = self.proxy_protocol else {
+            return self.get_connection(uri).await;
+        };
+
+        let key = PoolKey {
+            uri: uri.clone(),
+            header: Some(ctx.map_or_else(ProxyHeader::default, ProxyHeader::from_context).encode(version)),
+        };
+        match connections {
+            Some(ClientConnections(pool)) => self.get_pooled_connection(key, pool).await,
+            None => Ok(Reservation {
+                conn: self.connect(&key.uri, key.header.as_deref()).await?,
+                key,
+                pool: None,
+            }),
+        }
+    }
+
+    async fn get_pooled_connection(&self, key: PoolKey, pool: &Pool) -> Result<Reservation, HttpError> {
+        let mut pooled = pool.lock().await;
+        if let Some(conns) = pooled.get_mut(&key) {
             let mut conn = None;
-            while let Some(c) = pool.pop_front() {
-                if match &c {
-                    Connection::Http1(x) => x.is_ready() && !x.is_closed(),
-                    Connection::Http2(x) => x.is_ready() && !x.is_closed(),
-                    Connection::None => unreachable!(),
-                } {
+            while let Some(c) = conns.pop_front() {
+                if c.is_open() {
                     conn = Some(c);
                     break;
                 }
             }
+            if conns.is_empty() {
+                pooled.remove(&key);
+            }
 
             if let Some(conn) = conn {
                 return Ok(Reservation {
                     conn,
-                    uri: uri.clone(),
-                    pool: self.pool.clone(),
+                    key,
+                    pool: Some(pool.clone()),
                 });
             }
         }
+        drop(pooled);
 
-        let conn = self.connect(uri).await?;
+        let conn = self.connect(&key.uri, key.header.as_deref()).await?;
         Ok(Reservation {
             conn,
-            uri: uri.clone(),
-            pool: self.pool.clone(),
+            key,
+            pool: Some(pool.clone()),
         })
     }
 
-    async fn connect(&self, uri: &Uri) -> Result<Connection, HttpError> {
+    async fn connect(&self, uri: &Uri, header: Option<&[u8]>) -> Result<Connection, HttpError> {
         let (socket, version): (Box<dyn AsyncStream + Send + Unpin>, Version) =
             match uri.scheme().ok_or("No scheme specified")?.as_str() {
-                "unix" => (
-                    Box::new(UnixStream::connect(uri.path()).await?),
-                    Version::HTTP_11,
-                ),
+                "unix" => {
+                    let mut socket = UnixStream::connect(uri.path()).await?;
+                    if let Some(header) = header {
+                        socket.write_all(header).await?;
+                    }
+                    (Box::new(socket), Version::HTTP_11)
+                }
                 "http" => {
                     let host = uri.host().ok_or("No host specified")?.to_owned();
                     let port = uri.port_u16().unwrap_or(80);
                     let addr = format!("{}:{}", host, port);
-                    (Box::new(TcpStream::connect(addr).await?), Version::HTTP_11)
+                    let mut socket = TcpStream::connect(addr).await?;
+                    if let Some(header) = header {
+                        socket.write_all(header).await?;
+                    }
+                    (Box::new(socket), Version::HTTP_11)
                 }
                 "https" => {
                     let host = uri.host().ok_or("No host specified")?.to_owned();
                     let port = uri.port_u16().unwrap_or(443);
                     let addr = format!("{}:{}", host, port);
-                    let socket = TcpStream::connect(addr).await?;
+                    let mut socket = TcpStream::connect(addr).await?;
+                    if let Some(header) = header {
+                        socket.write_all(header).await?;
+                    }
                     let server_name = ServerName::try_from(host)
                         .or_else(|_| socket.peer_addr().map(|s| ServerName::IpAddress(s.ip().into())))?;
                     let stream = self.connector.connect(server_name, socket).await?;
@@ -185,3 +256,77 @@ impl Client {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::{BodyExt, Empty};
+
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    /// Answers every request with an empty response, reporting each connection and its end
+    async fn upstream() -> (Uri, mpsc::UnboundedReceiver<&'static str>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("http://{}/", listener.local_addr().unwrap()).parse().unwrap();
+        let (events, received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let events = events.clone();
+                events.send("open").unwrap();
+                tokio::spawn(async move {
+                    let mut buf = Vec::<u8>::new();
+                    let mut chunk = [0; 1024];
+                    while let Ok(length @ 1..) = stream.read(&mut chunk).await {
+                        buf.extend(&chunk[..length]);
+                        while let Some(end) = buf.windows(4).position(|x| x == b"\r\n\r\n") {
+                            buf.drain(..end + 4);
+                            stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").await.unwrap();
+                        }
+                    }
+                    events.send("closed").unwrap();
+                });
+            }
+        });
+        (uri, received)
+    }
+
+    async fn request(client: &Client, uri: &Uri, connections: Option<&ClientConnections>) {
+        let mut reservation = client.get_connection_for(uri, Some(&Context::default()), connections).await.unwrap();
+        let request = Request::builder().uri("/").body(Empty::new().map_err(From::from).boxed()).unwrap();
+        let response = reservation.send_request(request).await.unwrap();
+        response.into_body().collect().await.unwrap();
+        drop(reservation);
+        // Connections are put back into the pool by a task
+        tokio::task::yield_now().await;
+    }
+
+    #[tokio::test]
+    async fn proxy_protocol_connections_per_client() {
+        let (uri, mut events) = upstream().await;
+        let client = Client::with_proxy_protocol(Some(ProxyProtocolVersion::V1));
+
+        let connections = ClientConnections::default();
+        request(&client, &uri, Some(&connections)).await;
+        request(&client, &uri, Some(&connections)).await;
+        assert_eq!(events.recv().await, Some("open"));
+
+        // Another client gets its own connection
+        let other = ClientConnections::default();
+        request(&client, &uri, Some(&other)).await;
+        assert_eq!(events.recv().await, Some("open"));
+
+        drop((connections, other));
+        assert_eq!(events.recv().await, Some("closed"));
+        assert_eq!(events.recv().await, Some("closed"));
+
+        // Without a client connection to keep it for, the connection is closed after the request
+        request(&client, &uri, None).await;
+        assert_eq!(events.recv().await, Some("open"));
+        assert_eq!(events.recv().await, Some("closed"));
+        assert!(client.pool.lock().await.is_empty());
+    }
+}
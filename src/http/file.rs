@@ -34,7 +34,7 @@ impl HttpService for FileService {
         let meta = file.metadata().await?;
         let stream = ReaderStream::new(file);
         let body = StreamBody::new(
-            stream.map(|s| s.map(|b| Frame::data(b)).map_err(From::from)),
+            stream.map(|s| s.map(Frame::data).map_err(From::from)),
         );
         let mut builder = Response::builder().header(CONTENT_LENGTH, meta.len());
         if let Some(mime) = mime_guess::from_path(req.uri().path()).first_raw() {
@@ -0,0 +1,51 @@
+use async_trait::async_trait;
+
+use hyper::body::{Bytes, Incoming};
+use hyper::{Request, Response, StatusCode};
+
+use http_body_util::{combinators::BoxBody, BodyExt, Empty};
+
+use std::sync::Arc;
+
+use crate::handler::Context;
+use crate::settings;
+use crate::tls::TlsFingerprint;
+
+use super::{HttpError, HttpService};
+
+/// Rejects requests by the JA3 or JA4 fingerprint of the client
+pub struct FingerprintLayer {
+    service: Arc<dyn HttpService + Send + Sync>,
+    allow: Option<Vec<String>>,
+    deny: Vec<String>
+}
+
+impl FingerprintLayer {
+    pub fn new(service: Arc<dyn HttpService + Send + Sync>, settings: &settings::Fingerprints) -> Self {
+        Self {
+            service,
+            allow: settings.allow.clone(),
+            deny: settings.deny.clone().unwrap_or_default()
+        }
+    }
+
+    // Without an allow list everything not denied passes, including connections without TLS
+    fn allowed(&self, fingerprint: Option<&TlsFingerprint>) -> bool {
+        let matches = |list: &Vec<String>| fingerprint.is_some_and(|x| list.contains(&x.ja3) || list.contains(&x.ja4));
+        !matches(&self.deny) && self.allow.as_ref().is_none_or(matches)
+    }
+}
+
+#[async_trait]
+impl HttpService for FingerprintLayer {
+    async fn call(&self, req: Request<Incoming>) -> Result<Response<BoxBody<Bytes, HttpError>>, HttpError> {
+        let ctx = req.extensions().get::<Context>().unwrap();
+        if !self.allowed(ctx.tls_fingerprint.as_ref()) {
+            return Ok(Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(BoxBody::new(Empty::new().map_err(From::from)))?);
+        }
+
+        self.service.call(req).await
+    }
+}
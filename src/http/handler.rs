@@ -14,19 +14,22 @@ use hyper::{Request, Response, StatusCode, Version};
 
 use hyper_util::rt::{TokioIo, TokioExecutor};
 
-use http_body_util::{BodyExt, Empty};
+use http_body_util::{BodyExt, Empty, Full};
 use http_body_util::combinators::BoxBody;
 
 use crate::handler::{Handler, Context};
 use crate::http::utils::UriExt;
 use crate::io::ProxyStream;
+use crate::tls::acme;
 
+use super::client::ClientConnections;
 use super::{HttpError, HttpService};
 
 struct HyperService {
     service: Arc<dyn HttpService + Send + Sync>,
     ctx: Context,
-    http_ctx: HttpContext
+    http_ctx: HttpContext,
+    connections: ClientConnections
 }
 
 #[derive(Clone)]
@@ -35,7 +38,14 @@ pub struct HttpContext {
 }
 
 impl HyperService {
-
+    fn new(service: Arc<dyn HttpService + Send + Sync>, http_ctx: HttpContext, ctx: Context) -> Self {
+        Self {
+            service,
+            http_ctx,
+            connections: ClientConnections::default(),
+            ctx
+        }
+    }
 }
 
 impl Service<Request<Incoming>> for HyperService {
@@ -49,15 +59,26 @@ impl Service<Request<Incoming>> for HyperService {
 
         req.extensions_mut().insert(self.http_ctx.clone());
         req.extensions_mut().insert(self.ctx.clone());
+        req.extensions_mut().insert(self.connections.clone());
 
         Box::pin(async move {
-            if req.version() == Version::HTTP_2 {
-                if req.uri().authority().map(|e| e.host()) != server_name.as_ref().map(|e| e.as_str()) {
-                    return Ok(Response::builder()
-                        .status(StatusCode::MISDIRECTED_REQUEST)
-                        .body(BoxBody::new(Empty::new().map_err(From::from)))?
-                    );
-                }
+            // Connections without SNI, like h2 with prior knowledge, can't be misdirected
+            if req.version() == Version::HTTP_2
+                && server_name.is_some()
+                && req.uri().authority().map(|e| e.host()) != server_name.as_deref()
+            {
+                return Ok(Response::builder()
+                    .status(StatusCode::MISDIRECTED_REQUEST)
+                    .body(BoxBody::new(Empty::new().map_err(From::from)))?
+                );
+            }
+
+            // Answer pending HTTP-01 challenges before the request reaches the configured service
+            if let Some(key_authorization) = req.uri().path()
+                .strip_prefix("/.well-known/acme-challenge/")
+                .and_then(acme::http_challenge)
+            {
+                return Ok(Response::new(BoxBody::new(Full::from(key_authorization).map_err(From::from))));
             }
 
             *req.uri_mut() = req.uri().clone().normalize_path()?;
@@ -149,15 +170,21 @@ impl Http1Handler {
 #[async_trait]
 impl Handler for Http1Handler {
     async fn handle(&self, stream: ProxyStream, ctx: Context) -> Result<(), Box<dyn Error>> {
-        let service = HyperService {
-            service: self.service.clone(),
-            http_ctx: self.context.clone(),
-            ctx
-        };
-        self.builder
+        let shutdown = ctx.shutdown.clone();
+        let service = HyperService::new(self.service.clone(), self.context.clone(), ctx);
+        let conn = self.builder
             .serve_connection(TokioIo::new(stream), service)
-            .with_upgrades()
-            .await?;
+            .with_upgrades();
+        tokio::pin!(conn);
+
+        tokio::select! {
+            r = conn.as_mut() => r?,
+            _ = shutdown.cancelled() => {
+                // Finish the request in flight and close the connection
+                conn.as_mut().graceful_shutdown();
+                conn.await?;
+            }
+        }
 
         Ok(())
     }
@@ -194,14 +221,19 @@ impl Http2Handler {
 #[async_trait]
 impl Handler for Http2Handler {
     async fn handle(&self, stream: ProxyStream, ctx: Context) -> Result<(), Box<dyn Error>> {
-        let service = HyperService {
-            service: self.service.clone(),
-            http_ctx: self.context.clone(),
-            ctx
-        };
-        self.builder
-            .serve_connection(TokioIo::new(stream), service)
-            .await?;
+        let shutdown = ctx.shutdown.clone();
+        let service = HyperService::new(self.service.clone(), self.context.clone(), ctx);
+        let conn = self.builder.serve_connection(TokioIo::new(stream), service);
+        tokio::pin!(conn);
+
+        tokio::select! {
+            r = conn.as_mut() => r?,
+            _ = shutdown.cancelled() => {
+                // Send GOAWAY and let the open streams complete
+                conn.as_mut().graceful_shutdown();
+                conn.await?;
+            }
+        }
 
         Ok(())
     }
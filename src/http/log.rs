@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use async_trait::async_trait;
@@ -9,7 +9,7 @@ use http_body_util::combinators::BoxBody;
 
 use hyper::body::{Body, Bytes, Incoming};
 use hyper::{Request, Response};
-use tokio::fs::{File, OpenOptions};
+use tokio::fs::{self, File, OpenOptions};
 use tokio::io::AsyncWriteExt;
 use tokio::sync::Mutex;
 
@@ -20,23 +20,39 @@ use super::{HttpError, HttpService};
 
 pub struct LogLayer {
     service: Arc<dyn HttpService + Send + Sync>,
-    out: Mutex<File>
+    out: Mutex<File>,
+    fingerprints: bool
 }
 
 impl LogLayer {
-    pub async fn new(service: Arc<dyn HttpService + Send + Sync>, path: &PathBuf) -> Result<Self, Error> {
+    pub async fn new(service: Arc<dyn HttpService + Send + Sync>, path: &PathBuf, fingerprints: bool) -> Result<Self, Error> {
         Ok(Self {
             service,
-            out: Mutex::new(OpenOptions::new().create(true).append(true).open(path).await?)
+            out: Mutex::new(OpenOptions::new().create(true).append(true).open(path).await?),
+            fingerprints
         })
     }
+
+    /// Check that the log can be written without creating it
+    pub async fn check(path: &Path) -> Result<(), Error> {
+        match fs::try_exists(path).await? {
+            true => drop(OpenOptions::new().append(true).open(path).await?),
+            false => {
+                let dir = path.parent().filter(|x| !x.as_os_str().is_empty()).unwrap_or(Path::new("."));
+                if !fs::metadata(dir).await?.is_dir() {
+                    return Err(format!("{} is not a directory", dir.display()).into());
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
 impl HttpService for LogLayer {
     async fn call(&self, req: Request<Incoming>) -> Result<Response<BoxBody<Bytes, HttpError>>, HttpError> {
         let ctx = req.extensions().get::<Context>().unwrap();
-        let remote_addr = ctx.addr.map(|e| e.to_string()).unwrap_or("-".to_owned());
+        let remote_addr = ctx.addr.map(|e| e.ip().to_string()).unwrap_or("-".to_owned());
         let remote_user = "-";
 
         let now = Local::now();
@@ -44,12 +60,18 @@ impl HttpService for LogLayer {
         let request = req.uri().path_and_query().map(|e| e.to_string()).unwrap_or("/".to_owned());
         let http_referer = req.headers().get("Referer").map(|e| e.to_str().unwrap()).unwrap_or("-").to_owned();
         let http_user_agent = req.headers().get("User-Agent").map(|e| e.to_str().unwrap()).unwrap_or("-").to_owned();
+        // Combined log format, optionally followed by the JA3 and JA4 fingerprints of the client
+        let fingerprints = match (self.fingerprints, &ctx.tls_fingerprint) {
+            (true, Some(x)) => format!(" \"{}\" \"{}\"", x.ja3, x.ja4),
+            (true, None) => " \"-\" \"-\"".to_owned(),
+            (false, _) => String::new()
+        };
         let resp = self.service.call(req).await;
         match resp {
             Ok(resp) => {
                 let status = resp.status().as_u16();
                 let body_bytes_sent = resp.body().size_hint().lower();
-                let log = format!("{} - {} {} \"{}\" {} {} \"{}\" \"{}\"\n", remote_addr, remote_user, time_local, request, status, body_bytes_sent, http_referer, http_user_agent);
+                let log = format!("{} - {} {} \"{}\" {} {} \"{}\" \"{}\"{}\n", remote_addr, remote_user, time_local, request, status, body_bytes_sent, http_referer, http_user_agent, fingerprints);
                 {
                     let mut out = self.out.lock().await;
                     out.write_all(log.as_bytes()).await?;
@@ -58,7 +80,7 @@ impl HttpService for LogLayer {
                 Ok(resp)
             }
             Err(e) => {
-                let log = format!("{} - {} {} \"{}\" {} {} \"{}\" \"{}\"\n", remote_addr, remote_user, time_local, request, 500, 0, http_referer, http_user_agent);
+                let log = format!("{} - {} {} \"{}\" {} {} \"{}\" \"{}\"{}\n", remote_addr, remote_user, time_local, request, 500, 0, http_referer, http_user_agent, fingerprints);
                 {
                     let mut out = self.out.lock().await;
                     out.write_all(log.as_bytes()).await?;
@@ -0,0 +1,30 @@
+use async_trait::async_trait;
+
+use hyper::{
+    body::{Bytes, Incoming},
+    header, Request, Response,
+};
+
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+
+use crate::http::HttpService;
+use crate::tls::handshake;
+
+use super::HttpError;
+
+/// Serves the proxy counters in the Prometheus text format
+pub struct MetricsService {}
+
+#[async_trait]
+impl HttpService for MetricsService {
+    async fn call(&self, _: Request<Incoming>) -> Result<Response<BoxBody<Bytes, HttpError>>, HttpError> {
+        let mut s = String::new();
+        handshake::write_metrics(&mut s);
+        let body = BoxBody::new(
+            Full::new(Bytes::from(s)).map_err(From::from),
+        );
+        Ok(Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(body)?)
+    }
+}
@@ -1,20 +1,28 @@
 mod authenticator;
+mod certificate;
 mod client;
 mod handler;
 mod log;
+mod metrics;
 mod proxy;
 mod service;
 mod utils;
 mod hello;
 mod file;
+mod fingerprint;
 mod router;
+mod tls_headers;
 
 pub use authenticator::*;
+pub use certificate::*;
 pub use client::*;
 pub use handler::*;
 pub use log::*;
+pub use metrics::*;
 pub use proxy::*;
 pub use service::*;
 pub use hello::*;
 pub use file::*;
+pub use fingerprint::*;
 pub use router::*;
+pub use tls_headers::*;
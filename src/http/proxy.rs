@@ -16,7 +16,10 @@ use hyper::{header, Request, Response, StatusCode, Uri, Version};
 
 use tokio::io::copy_bidirectional;
 
-use super::client::{Client, Connection};
+use crate::handler::Context;
+use crate::settings::ProxyProtocolVersion;
+
+use super::client::{Client, ClientConnections, Connection};
 use super::{HttpError, HttpService};
 
 pub struct ProxyService {
@@ -25,9 +28,9 @@ pub struct ProxyService {
 }
 
 impl ProxyService {
-    pub fn new(uri: Uri) -> Self {
+    pub fn new(uri: Uri, proxy_protocol: Option<ProxyProtocolVersion>) -> Self {
         ProxyService {
-            client: Client::new(),
+            client: Client::with_proxy_protocol(proxy_protocol),
             uri,
         }
     }
@@ -35,14 +38,14 @@ impl ProxyService {
 
 #[async_trait]
 impl HttpService for ProxyService {
-    async fn call(&self, mut req: Request<Incoming>) -> Result<Response<BoxBody<Bytes, HttpError>>, HttpError> {
+    async fn call(&self, req: Request<Incoming>) -> Result<Response<BoxBody<Bytes, HttpError>>, HttpError> {
         let (req_parts, body) = req.into_parts();
 
         let mut parts = req_parts.uri.clone().into_parts();
         parts.scheme = None;
         parts.authority = None;
 
-        let mut sender = self.client.get_connection(&self.uri).await?;
+        let mut sender = self.client.get_connection_for(&self.uri, req_parts.extensions.get::<Context>(), req_parts.extensions.get::<ClientConnections>()).await?;
 
         let mut proxy_body = BoxBody::new(body.map_err(From::from));
         let mut req_body: BoxBody<Bytes, HttpError> = BoxBody::new(Empty::new().map_err(From::from));
@@ -67,38 +70,35 @@ impl HttpService for ProxyService {
         let mut request = request.method(req_parts.method.clone()).body(proxy_body)?;
         let headers = request.headers_mut();
         headers.clone_from(&req_parts.headers);
-        match sender.conn {
-            Connection::Http1(_) => {
-                let host = match req_parts.version {
-                    Version::HTTP_2 => {
-                        if headers.contains_key(header::COOKIE) {
-                            // Concat cookies for requests from Http/2 to Http/1.1
-                            headers.insert(
-                                header::COOKIE,
-                                HeaderValue::from_str(
-                                    &headers
-                                        .get_all(header::COOKIE)
-                                        .into_iter()
-                                        .filter_map(|h| h.to_str().ok())
-                                        .join("; "),
-                                )?,
-                            );
-                        }
-                        req_parts.uri.authority().map(Authority::host)
+        if let Connection::Http1(_) = sender.conn {
+            let host = match req_parts.version {
+                Version::HTTP_2 => {
+                    if headers.contains_key(header::COOKIE) {
+                        // Concat cookies for requests from Http/2 to Http/1.1
+                        headers.insert(
+                            header::COOKIE,
+                            HeaderValue::from_str(
+                                &headers
+                                    .get_all(header::COOKIE)
+                                    .into_iter()
+                                    .filter_map(|h| h.to_str().ok())
+                                    .join("; "),
+                            )?,
+                        );
                     }
-                    _ => req_parts
-                        .headers
-                        .get(header::HOST)
-                        .and_then(|e| e.to_str().ok()),
-                };
-
-                if let Some(host) = host {
-                    headers
-                        .entry(header::HOST)
-                        .or_insert(HeaderValue::from_str(host)?);
+                    req_parts.uri.authority().map(Authority::host)
                 }
+                _ => req_parts
+                    .headers
+                    .get(header::HOST)
+                    .and_then(|e| e.to_str().ok()),
+            };
+
+            if let Some(host) = host {
+                headers
+                    .entry(header::HOST)
+                    .or_insert(HeaderValue::from_str(host)?);
             }
-            _ => (),
         };
 
         let response = sender.send_request(request).await?;
@@ -109,7 +109,9 @@ impl HttpService for ProxyService {
             *upgrade_response.headers_mut() = response.headers().clone();
             *upgrade_response.status_mut() = response.status();
             let mut upgrade = hyper::upgrade::on(response).await?;
-            tokio::spawn(async move {
+            // Upgraded connections outlive the request, the drain waits for them like for any connection
+            let shutdown = req_parts.extensions.get::<Context>().map(|x| x.shutdown.clone()).unwrap_or_default();
+            shutdown.spawn(async move {
                 match hyper::upgrade::on(Request::from_parts(req_parts, req_body)).await {
                     Ok(mut upstream_upgrade) => {
                         if let Err(e) = copy_bidirectional(&mut TokioIo::new(&mut upgrade), &mut TokioIo::new(&mut upstream_upgrade)).await {
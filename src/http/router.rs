@@ -7,12 +7,15 @@ use hyper::{Request, Response, Uri};
 
 use http_body_util::combinators::BoxBody;
 
+use crate::handler::Context;
 use crate::http::HttpService;
 
 use super::HttpError;
 
 pub struct Route {
     pub route: String,
+    /// Only clients with one of these JA3 or JA4 fingerprints take the route
+    pub fingerprints: Option<Vec<String>>,
     pub service: Arc<dyn HttpService + Send + Sync>,
 }
 
@@ -33,11 +36,16 @@ impl RouterService {
 #[async_trait]
 impl HttpService for RouterService {
     async fn call(&self, mut req: Request<Incoming>) -> Result<Response<BoxBody<Bytes, HttpError>>, HttpError> {
+        let fingerprint = req.extensions().get::<Context>().and_then(|x| x.tls_fingerprint.as_ref());
         let (index, prefix) = self
             .prefixes
             .iter()
             .enumerate()
-            .find(|(_, prefix)| req.uri().path().starts_with(*prefix))
+            .find(|(index, prefix)| {
+                req.uri().path().starts_with(*prefix)
+                    && self.routes[*index].fingerprints.as_ref()
+                        .is_none_or(|x| fingerprint.is_some_and(|f| x.contains(&f.ja3) || x.contains(&f.ja4)))
+            })
             .ok_or("No route")?;
 
         let route = self.routes.get(index).unwrap();
@@ -62,6 +62,12 @@ impl From<std::io::Error> for HttpError {
     }
 }
 
+impl From<Box<dyn Error + Send + Sync>> for HttpError {
+    fn from(error: Box<dyn Error + Send + Sync>) -> Self {
+        HttpError::Other(error)
+    }
+}
+
 impl From<Infallible> for HttpError {
     fn from(_: Infallible) -> Self {
         unreachable!()
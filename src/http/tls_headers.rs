@@ -0,0 +1,67 @@
+use async_trait::async_trait;
+
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Request, Response};
+
+use http_body_util::combinators::BoxBody;
+
+use std::sync::Arc;
+
+use crate::error::Error;
+use crate::handler::Context;
+use crate::settings;
+
+use super::{HttpError, HttpService};
+
+type HeaderValueFn = fn(&Context) -> Option<String>;
+
+/// Passes details of the TLS session and client certificate to the upstream as request headers
+pub struct TlsHeadersLayer {
+    service: Arc<dyn HttpService + Send + Sync>,
+    headers: Vec<(HeaderName, HeaderValueFn)>
+}
+
+impl TlsHeadersLayer {
+    pub fn new(service: Arc<dyn HttpService + Send + Sync>, settings: &settings::TlsHeaders) -> Result<Self, Error> {
+        let fields: [(&Option<String>, HeaderValueFn); 11] = [
+            (&settings.subject, |ctx| ctx.client_certificate.as_ref().map(|x| x.subject.clone())),
+            (&settings.certificate, |ctx| ctx.client_certificate.as_ref().map(|x| form_urlencoded::byte_serialize(x.pem().as_bytes()).collect())),
+            (&settings.fingerprint, |ctx| ctx.client_certificate.as_ref().map(|x| x.fingerprint.clone())),
+            (&settings.version, |ctx| ctx.tls_version.clone()),
+            (&settings.cipher, |ctx| ctx.cipher.clone()),
+            (&settings.server_name, |ctx| ctx.server_name.clone()),
+            (&settings.alpn, |ctx| ctx.alpn.clone()),
+            (&settings.ja3, |ctx| ctx.tls_fingerprint.as_ref().map(|x| x.ja3.clone())),
+            (&settings.ja4, |ctx| ctx.tls_fingerprint.as_ref().map(|x| x.ja4.clone())),
+            (&settings.offered_alpn, |ctx| ctx.tls_fingerprint.as_ref().map(|x| x.alpn.join(","))),
+            (&settings.offered_ciphers, |ctx| ctx.tls_fingerprint.as_ref().map(|x| x.cipher_suites.join(",")))
+        ];
+
+        let headers = fields.into_iter()
+            .filter_map(|(name, value)| name.as_ref().map(|x| Ok::<_, Error>((HeaderName::try_from(x)?, value))))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            service,
+            headers
+        })
+    }
+}
+
+#[async_trait]
+impl HttpService for TlsHeadersLayer {
+    async fn call(&self, mut req: Request<Incoming>) -> Result<Response<BoxBody<Bytes, HttpError>>, HttpError> {
+        let ctx = req.extensions().get::<Context>().unwrap().clone();
+        let headers = req.headers_mut();
+        for (name, value) in &self.headers {
+            // Client supplied copies are always dropped so they can't be mistaken for verified values
+            headers.remove(name);
+            if let Some(value) = value(&ctx).and_then(|x| HeaderValue::try_from(x).ok()) {
+                headers.insert(name, value);
+            }
+        }
+
+        self.service.call(req).await
+    }
+}
@@ -4,7 +4,7 @@ use std::pin::Pin;
 use ktls::AsyncReadReady;
 
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::TcpStream;
+use tokio::net::{TcpStream, UnixStream};
 
 pub trait AsyncStream: AsyncWrite + AsyncRead {}
 
@@ -14,7 +14,16 @@ pub type SendableAsyncStream = Pin<Box<dyn AsyncStream + Send + Sync>>;
 
 pub enum ProxyStream {
     Tcp(TcpStream),
-    Dynamic(Pin<Box<dyn AsyncStream + Send + Sync>>)
+    Unix(UnixStream),
+    Dynamic(Pin<Box<dyn AsyncStream + Send + Sync>>),
+    Prefixed(Box<PrefixedStream>)
+}
+
+/// Stream which first replays bytes already read from the inner stream
+pub struct PrefixedStream {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: ProxyStream
 }
 
 impl ProxyStream {
@@ -22,9 +31,21 @@ impl ProxyStream {
         ProxyStream::Tcp(stream)
     }
 
+    pub fn new_unix(stream: UnixStream) -> Self {
+        ProxyStream::Unix(stream)
+    }
+
     pub fn new_dynamic(stream: SendableAsyncStream) -> Self {
         ProxyStream::Dynamic(stream)
     }
+
+    pub fn new_prefixed(prefix: Vec<u8>, stream: ProxyStream) -> Self {
+        ProxyStream::Prefixed(Box::new(PrefixedStream {
+            prefix,
+            pos: 0,
+            inner: stream
+        }))
+    }
 }
 
 impl AsyncRead for ProxyStream {
@@ -35,7 +56,17 @@ impl AsyncRead for ProxyStream {
     ) -> std::task::Poll<std::io::Result<()>> {
         match self.get_mut() {
             ProxyStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
-            ProxyStream::Dynamic(stream) => Pin::new(stream).poll_read(cx, buf)
+            ProxyStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            ProxyStream::Dynamic(stream) => Pin::new(stream).poll_read(cx, buf),
+            ProxyStream::Prefixed(stream) => {
+                if stream.pos < stream.prefix.len() {
+                    let n = buf.remaining().min(stream.prefix.len() - stream.pos);
+                    buf.put_slice(&stream.prefix[stream.pos..stream.pos + n]);
+                    stream.pos += n;
+                    return std::task::Poll::Ready(Ok(()));
+                }
+                Pin::new(&mut stream.inner).poll_read(cx, buf)
+            }
         }
     }
 }
@@ -48,7 +79,9 @@ impl AsyncWrite for ProxyStream {
     ) -> std::task::Poll<std::io::Result<usize>> {
         match self.get_mut() {
             ProxyStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
-            ProxyStream::Dynamic(stream) => Pin::new(stream).poll_write(cx, buf)
+            ProxyStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            ProxyStream::Dynamic(stream) => Pin::new(stream).poll_write(cx, buf),
+            ProxyStream::Prefixed(stream) => Pin::new(&mut stream.inner).poll_write(cx, buf)
         }
     }
 
@@ -58,7 +91,9 @@ impl AsyncWrite for ProxyStream {
     ) -> std::task::Poll<std::io::Result<()>> {
         match self.get_mut() {
             ProxyStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
-            ProxyStream::Dynamic(stream) => Pin::new(stream).poll_flush(cx)
+            ProxyStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            ProxyStream::Dynamic(stream) => Pin::new(stream).poll_flush(cx),
+            ProxyStream::Prefixed(stream) => Pin::new(&mut stream.inner).poll_flush(cx)
         }
     }
 
@@ -68,7 +103,9 @@ impl AsyncWrite for ProxyStream {
     ) -> std::task::Poll<std::io::Result<()>> {
         match self.get_mut() {
             ProxyStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
-            ProxyStream::Dynamic(stream) => Pin::new(stream).poll_shutdown(cx)
+            ProxyStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            ProxyStream::Dynamic(stream) => Pin::new(stream).poll_shutdown(cx),
+            ProxyStream::Prefixed(stream) => Pin::new(&mut stream.inner).poll_shutdown(cx)
         }
     }
 }
@@ -77,7 +114,10 @@ impl AsyncReadReady for ProxyStream {
     fn poll_read_ready(&self, cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
         match self {
             ProxyStream::Tcp(stream) => stream.poll_read_ready(cx),
-            ProxyStream::Dynamic(_) => std::task::Poll::Ready(Ok(()))
+            ProxyStream::Unix(stream) => stream.poll_read_ready(cx),
+            ProxyStream::Dynamic(_) => std::task::Poll::Ready(Ok(())),
+            ProxyStream::Prefixed(stream) if stream.pos < stream.prefix.len() => std::task::Poll::Ready(Ok(())),
+            ProxyStream::Prefixed(stream) => stream.inner.poll_read_ready(cx)
         }
     }
 }
@@ -86,7 +126,10 @@ impl AsRawFd for ProxyStream {
     fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
         match self {
             ProxyStream::Tcp(stream) => stream.as_raw_fd(),
-            ProxyStream::Dynamic(_) => -1.as_raw_fd()
+            ProxyStream::Unix(stream) => stream.as_raw_fd(),
+            ProxyStream::Dynamic(_) => -1.as_raw_fd(),
+            // Kernel TLS can take over once the handshake consumed the replayed bytes
+            ProxyStream::Prefixed(stream) => stream.inner.as_raw_fd()
         }
     }
 }
@@ -1,42 +1,184 @@
 use async_trait::async_trait;
 
+use nix::unistd::{Gid, Group, Uid, User};
+
 use tokio::net;
 use tokio::io;
+use tokio::time::sleep;
 
-use std::sync::Arc;
+use std::fs::{self, Permissions};
+use std::io::ErrorKind;
+use std::os::fd::OwnedFd;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{SocketAddr, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
+use crate::activation::ListenFds;
 use crate::handler::Context;
 use crate::handler::Handler;
 use crate::handler::SendableHandler;
 use crate::io::ProxyStream;
+use crate::settings;
+use crate::shutdown::Shutdown;
+
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
 
 #[async_trait]
 pub trait Listener {
-    async fn handle(&self);
+    async fn handle(&self, shutdown: &Shutdown);
+
+    /// Replace the handler for new connections, connections in flight keep the previous one
+    fn set_handler(&self, handler: SendableHandler);
+
+    /// Change the mode and ownership of the socket file, when there is one
+    fn set_permissions(&self, _permissions: &SocketPermissions) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 pub struct TcpListener {
     listener: net::TcpListener,
-    handler: Arc<dyn Handler + Send + Sync>
+    handler: RwLock<Arc<dyn Handler + Send + Sync>>
+}
+
+pub struct UnixListener {
+    listener: net::UnixListener,
+    path: Option<PathBuf>,
+    handler: RwLock<Arc<dyn Handler + Send + Sync>>
+}
+
+/// Mode and ownership of a socket file, resolved before anything is bound so an unknown user or group fails early
+#[derive(Debug, Default)]
+pub struct SocketPermissions {
+    mode: Option<u32>,
+    owner: Option<Uid>,
+    group: Option<Gid>
 }
 
 impl TcpListener {
-    pub async fn new(listen: &str, handler: SendableHandler) -> io::Result<Self> {
+    pub async fn new(settings: &settings::SocketListener, handler: SendableHandler, listen_fds: &ListenFds) -> io::Result<Self> {
+        // Use the socket handed over by the service manager, so privileged ports don't require root
+        if let Some(name) = &settings.fd_name {
+            let listener = std::net::TcpListener::from(take_listen_fd(listen_fds, name)?);
+            listener.local_addr()?;
+            listener.set_nonblocking(true)?;
+
+            return Ok(Self {
+                listener: net::TcpListener::from_std(listener)?,
+                handler: RwLock::new(handler.into())
+            });
+        }
+
+        Ok(Self {
+            listener: net::TcpListener::bind(&settings.listen).await?,
+            handler: RwLock::new(handler.into())
+        })
+    }
+}
+
+impl UnixListener {
+    pub fn new(settings: &settings::UnixListener, handler: SendableHandler, listen_fds: &ListenFds) -> io::Result<Self> {
+        // Inherited sockets are owned by the service manager, so the socket file is left alone
+        if let Some(name) = &settings.fd_name {
+            let listener = std::os::unix::net::UnixListener::from(take_listen_fd(listen_fds, name)?);
+            listener.local_addr()?;
+            listener.set_nonblocking(true)?;
+
+            return Ok(Self {
+                listener: net::UnixListener::from_std(listener)?,
+                path: None,
+                handler: RwLock::new(handler.into())
+            });
+        }
+
+        // Paths starting with '@' refer to the Linux abstract namespace and have no file to manage
+        if let Some(name) = settings.path.strip_prefix('@') {
+            let addr = SocketAddr::from_abstract_name(name)?;
+            let listener = std::os::unix::net::UnixListener::bind_addr(&addr)?;
+            listener.set_nonblocking(true)?;
+
+            return Ok(Self {
+                listener: net::UnixListener::from_std(listener)?,
+                path: None,
+                handler: RwLock::new(handler.into())
+            });
+        }
+
+        let path = PathBuf::from(&settings.path);
+        let permissions = SocketPermissions::new(settings)?;
+        if settings.remove_stale.unwrap_or(true) {
+            remove_stale_socket(&path)?;
+        }
+
+        let listener = net::UnixListener::bind(&path)?;
+        permissions.apply(&path)?;
+
+        Ok(Self {
+            listener,
+            path: Some(path),
+            handler: RwLock::new(handler.into())
+        })
+    }
+}
+
+impl SocketPermissions {
+    pub fn new(settings: &settings::UnixListener) -> io::Result<Self> {
+        let mode = settings.mode.as_deref()
+            .map(|mode| u32::from_str_radix(mode, 8).map_err(|_| io::Error::new(ErrorKind::InvalidInput, format!("Invalid socket mode {}", mode))))
+            .transpose()?;
+
         Ok(Self {
-            listener: net::TcpListener::bind(listen).await?,
-            handler: handler.into()
+            mode,
+            owner: settings.owner.as_deref().map(lookup_user).transpose()?,
+            group: settings.group.as_deref().map(lookup_group).transpose()?
         })
     }
+
+    fn apply(&self, path: &Path) -> io::Result<()> {
+        if let Some(mode) = self.mode {
+            fs::set_permissions(path, Permissions::from_mode(mode))?;
+        }
+        if self.owner.is_some() || self.group.is_some() {
+            std::os::unix::fs::chown(path, self.owner.map(Uid::as_raw), self.group.map(Gid::as_raw))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for UnixListener {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = fs::remove_file(path);
+        }
+    }
 }
 
 #[async_trait]
 impl Listener for TcpListener {
-    async fn handle(&self) {
-        while let Ok((stream, _)) = self.listener.accept().await {
-            let handler = self.handler.clone();
-            tokio::spawn(async move {
-                let mut ctx = Context::default();
-                ctx.addr = stream.peer_addr().ok().map(|x| x.ip());
+    async fn handle(&self, shutdown: &Shutdown) {
+        loop {
+            let stream = tokio::select! {
+                r = self.listener.accept() => match r {
+                    Ok((stream, _)) => stream,
+                    Err(e) => match accept_failed(e, shutdown).await {
+                        true => continue,
+                        false => break
+                    }
+                },
+                _ = shutdown.cancelled() => break
+            };
+
+            let handler = self.handler.read().unwrap().clone();
+            let ctx = Context {
+                addr: stream.peer_addr().ok(),
+                local_addr: stream.local_addr().ok(),
+                shutdown: shutdown.clone(),
+                ..Default::default()
+            };
+            shutdown.spawn(async move {
                 let r = handler.handle(ProxyStream::new_tcp(stream), ctx).await;
                 if let Err(e) = r {
                     println!("Error while handling {}", e);
@@ -44,4 +186,103 @@ impl Listener for TcpListener {
             });
         }
     }
+
+    fn set_handler(&self, handler: SendableHandler) {
+        *self.handler.write().unwrap() = handler.into();
+    }
+}
+
+#[async_trait]
+impl Listener for UnixListener {
+    async fn handle(&self, shutdown: &Shutdown) {
+        loop {
+            let stream = tokio::select! {
+                r = self.listener.accept() => match r {
+                    Ok((stream, _)) => stream,
+                    Err(e) => match accept_failed(e, shutdown).await {
+                        true => continue,
+                        false => break
+                    }
+                },
+                _ = shutdown.cancelled() => break
+            };
+
+            let handler = self.handler.read().unwrap().clone();
+            // Unix peers have no IP address, so the context is left at its local defaults
+            let ctx = Context {
+                shutdown: shutdown.clone(),
+                ..Default::default()
+            };
+            shutdown.spawn(async move {
+                let r = handler.handle(ProxyStream::new_unix(stream), ctx).await;
+                if let Err(e) = r {
+                    println!("Error while handling {}", e);
+                }
+            });
+        }
+    }
+
+    fn set_handler(&self, handler: SendableHandler) {
+        *self.handler.write().unwrap() = handler.into();
+    }
+
+    fn set_permissions(&self, permissions: &SocketPermissions) -> io::Result<()> {
+        match &self.path {
+            Some(path) => permissions.apply(path),
+            None => Ok(())
+        }
+    }
+}
+
+/// Errors like running out of file descriptors are transient, accepting resumes after a moment unless shutting down
+async fn accept_failed(e: io::Error, shutdown: &Shutdown) -> bool {
+    eprintln!("Failed to accept connection: {}", e);
+    tokio::select! {
+        _ = sleep(ACCEPT_BACKOFF) => true,
+        _ = shutdown.cancelled() => false
+    }
+}
+
+fn take_listen_fd(listen_fds: &ListenFds, name: &str) -> io::Result<OwnedFd> {
+    listen_fds.take(name)
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("No socket named {} was passed by the service manager", name)))
+}
+
+/// Remove a socket file left behind by a previous process, refusing to touch live sockets or other files
+fn remove_stale_socket(path: &PathBuf) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e)
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display())));
+    }
+
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(ErrorKind::AddrInUse, format!("{} is in use by another process", path.display()))),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(e) => Err(e)
+    }
+}
+
+fn lookup_user(name: &str) -> io::Result<Uid> {
+    if let Ok(uid) = name.parse() {
+        return Ok(Uid::from_raw(uid));
+    }
+
+    User::from_name(name)?
+        .map(|x| x.uid)
+        .ok_or(io::Error::new(ErrorKind::NotFound, format!("Unknown user {}", name)))
+}
+
+fn lookup_group(name: &str) -> io::Result<Gid> {
+    if let Ok(gid) = name.parse() {
+        return Ok(Gid::from_raw(gid));
+    }
+
+    Group::from_name(name)?
+        .map(|x| x.gid)
+        .ok_or(io::Error::new(ErrorKind::NotFound, format!("Unknown group {}", name)))
 }
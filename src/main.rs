@@ -1,23 +1,136 @@
+mod activation;
+mod address;
+mod balancer;
+mod detect;
 mod handler;
 mod io;
 mod listener;
+mod proxy_protocol;
+mod server;
 mod settings;
+mod shutdown;
+mod signal;
+mod starttls;
 
 mod http;
 mod tls;
 mod tunnel;
 mod error;
 
-use futures::future::{join_all, try_join_all};
-use settings::{build_listener, Settings};
+use clap::Parser;
+
+use tokio::runtime::Runtime;
+
+use activation::ListenFds;
+use server::Server;
+use settings::Settings;
+use shutdown::Shutdown;
+use signal::{Event, Signals};
 use error::Error;
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
-    let settings = Settings::new()?;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+
+/// TCP/TLS/HTTPS proxy and gateway
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Configuration file
+    #[arg(short, long, default_value = "config.yaml")]
+    config: PathBuf,
+
+    /// Validate the configuration by building all handlers without binding sockets, then exit
+    #[arg(long)]
+    check: bool,
+
+    /// Print the resolved configuration as JSON, then exit
+    #[arg(long)]
+    dump: bool
+}
+
+fn main() -> Result<ExitCode, Error> {
+    let args = Args::parse();
+    // Taken before the runtime starts its threads, the environment can't be changed safely afterwards
+    let listen_fds = ListenFds::from_env();
+
+    Runtime::new()?.block_on(start(args, listen_fds))
+}
+
+async fn start(args: Args, listen_fds: ListenFds) -> Result<ExitCode, Error> {
+
+    let settings = match Settings::new(&args.config) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Invalid configuration {}: {}", args.config.display(), e);
+            return Ok(ExitCode::FAILURE);
+        }
+    };
+
+    if args.dump {
+        println!("{}", serde_json::to_string_pretty(&settings)?);
+    }
+
+    if args.check {
+        return Ok(match server::check(&settings).await {
+            Ok(()) => {
+                println!("Configuration {} is valid", args.config.display());
+                ExitCode::SUCCESS
+            },
+            Err(e) => {
+                eprintln!("Invalid configuration {}: {}", args.config.display(), e);
+                ExitCode::FAILURE
+            }
+        });
+    }
+
+    if args.dump {
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    run(settings, &args.config, listen_fds).await
+}
+
+async fn run(mut settings: Settings, path: &Path, listen_fds: ListenFds) -> Result<ExitCode, Error> {
+    let mut signals = Signals::new()?;
+    let shutdown = Shutdown::default();
+
+    let mut server = Server::new(shutdown.clone(), listen_fds);
+    server.load(&settings).await?;
+
+    loop {
+        match signals.recv().await {
+            Event::Terminate => break,
+            Event::Reload => {
+                let reloaded = match Settings::new(path) {
+                    Ok(reloaded) => server.load(&reloaded).await.map(|_| reloaded),
+                    Err(e) => Err(e.into())
+                };
+                match reloaded {
+                    Ok(reloaded) => {
+                        println!("Configuration reloaded");
+                        settings = reloaded;
+                    },
+                    Err(e) => eprintln!("Failed to reload configuration, keeping the running configuration: {}", e)
+                }
+            }
+        }
+    }
+
+    println!("Shutting down, draining connections");
+    shutdown.trigger();
 
-    let listeners = try_join_all(settings.servers.iter().map(build_listener)).await?;
-    join_all(listeners.iter().map(|l| l.handle())).await;
+    let drain_timeout = Duration::from_secs(settings.drain_timeout.unwrap_or(30));
+    let drained = tokio::select! {
+        drained = shutdown.drain(drain_timeout) => drained,
+        // A second termination signal skips the drain
+        _ = async { while let Event::Reload = signals.recv().await {} } => false
+    };
 
-    Ok(())
+    if drained {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        eprintln!("Connections still open after drain, closing them");
+        Ok(ExitCode::FAILURE)
+    }
 }
@@ -0,0 +1,595 @@
+use async_trait::async_trait;
+
+use ipnet::IpNet;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::timeout;
+
+use std::error::Error;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::{self, FromStr};
+use std::time::Duration;
+
+use crate::handler::{Context, Handler, SendableHandler};
+use crate::io::ProxyStream;
+use crate::settings::ProxyProtocolVersion;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const V1_PREFIX: &[u8] = b"PROXY";
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+const PP2_CMD_LOCAL: u8 = 0x0;
+const PP2_CMD_PROXY: u8 = 0x1;
+
+const PP2_FAM_UNSPEC: u8 = 0x00;
+const PP2_FAM_TCP4: u8 = 0x11;
+const PP2_FAM_UDP4: u8 = 0x12;
+const PP2_FAM_TCP6: u8 = 0x21;
+const PP2_FAM_UDP6: u8 = 0x22;
+const PP2_FAM_UNIX_STREAM: u8 = 0x31;
+const PP2_FAM_UNIX_DGRAM: u8 = 0x32;
+
+const PP2_TYPE_ALPN: u8 = 0x01;
+const PP2_TYPE_AUTHORITY: u8 = 0x02;
+const PP2_TYPE_SSL: u8 = 0x20;
+
+const PP2_CLIENT_SSL: u8 = 0x01;
+
+/// Connection details carried by a PROXY protocol header
+#[derive(Debug, Default, Clone)]
+pub struct ProxyHeader {
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+    pub alpn: Option<String>,
+    pub authority: Option<String>,
+    // Client flags of the SSL TLV
+    pub ssl: Option<u8>
+}
+
+impl ProxyHeader {
+    pub fn from_context(ctx: &Context) -> Self {
+        Self {
+            source: ctx.addr,
+            destination: ctx.local_addr,
+            alpn: ctx.alpn.clone(),
+            authority: ctx.server_name.clone(),
+            ssl: ctx.secure.then_some(PP2_CLIENT_SSL)
+        }
+    }
+
+    pub fn encode(&self, version: ProxyProtocolVersion) -> Vec<u8> {
+        match version {
+            ProxyProtocolVersion::V1 => self.encode_v1(),
+            ProxyProtocolVersion::V2 => self.encode_v2()
+        }
+    }
+
+    fn addresses(&self) -> Option<(SocketAddr, SocketAddr)> {
+        match (self.source?, self.destination?) {
+            (SocketAddr::V4(source), SocketAddr::V4(destination)) => Some((source.into(), destination.into())),
+            // Mixed families are sent as IPv6 with the IPv4 address mapped into it
+            (source, destination) => Some((to_ipv6(source), to_ipv6(destination)))
+        }
+    }
+
+    fn encode_v1(&self) -> Vec<u8> {
+        match self.addresses() {
+            Some((source, destination)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            ).into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec()
+        }
+    }
+
+    fn encode_v2(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        let (command, family) = match self.addresses() {
+            Some((SocketAddr::V4(source), SocketAddr::V4(destination))) => {
+                payload.extend(source.ip().octets());
+                payload.extend(destination.ip().octets());
+                payload.extend(source.port().to_be_bytes());
+                payload.extend(destination.port().to_be_bytes());
+                (PP2_CMD_PROXY, PP2_FAM_TCP4)
+            },
+            Some((source, destination)) => {
+                payload.extend(to_ipv6_addr(source.ip()).octets());
+                payload.extend(to_ipv6_addr(destination.ip()).octets());
+                payload.extend(source.port().to_be_bytes());
+                payload.extend(destination.port().to_be_bytes());
+                (PP2_CMD_PROXY, PP2_FAM_TCP6)
+            },
+            // Without a client address the connection is announced as the proxy's own
+            None => (PP2_CMD_LOCAL, PP2_FAM_UNSPEC)
+        };
+
+        if let Some(alpn) = &self.alpn {
+            push_tlv(&mut payload, PP2_TYPE_ALPN, alpn.as_bytes());
+        }
+        if let Some(authority) = &self.authority {
+            push_tlv(&mut payload, PP2_TYPE_AUTHORITY, authority.as_bytes());
+        }
+        if let Some(client) = self.ssl {
+            // A non-zero verify field signals that no client certificate was verified
+            let mut ssl = vec![client];
+            ssl.extend(1u32.to_be_bytes());
+            push_tlv(&mut payload, PP2_TYPE_SSL, &ssl);
+        }
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend((payload.len() as u16).to_be_bytes());
+        header.extend(payload);
+        header
+    }
+}
+
+/// Takes the client address from the PROXY protocol header of trusted senders
+pub struct ProxyProtocolHandler {
+    trusted: Vec<IpNet>,
+    trust_unix: bool,
+    handler: SendableHandler,
+    timeout: Duration
+}
+
+impl ProxyProtocolHandler {
+    pub fn new(trusted: &[String], trust_unix: bool, handler: SendableHandler, timeout: Option<u64>) -> Result<Self, ipnet::AddrParseError> {
+        Ok(Self {
+            trusted: trusted.iter().map(|x| parse_net(x)).collect::<Result<Vec<_>, _>>()?,
+            trust_unix,
+            handler,
+            timeout: timeout.map(Duration::from_millis).unwrap_or(DEFAULT_TIMEOUT)
+        })
+    }
+}
+
+#[async_trait]
+impl Handler for ProxyProtocolHandler {
+    async fn handle(&self, mut stream: ProxyStream, mut ctx: Context) -> Result<(), Box<dyn Error>> {
+        match ctx.addr.map(|x| x.ip()) {
+            Some(addr) if !self.trusted.iter().any(|x| x.contains(&addr)) => return Err(format!("Untrusted PROXY protocol sender {}", addr).into()),
+            // Peers of a unix listener have no address to check
+            None if !self.trust_unix => return Err("PROXY protocol sender has no address, set trust_unix to accept unix socket peers".into()),
+            _ => {}
+        }
+
+        let (header, rest) = timeout(self.timeout, read_header(&mut stream)).await
+            .map_err(|_| io::Error::new(ErrorKind::TimedOut, "PROXY protocol header timed out"))??;
+        if let Some(source) = header.source {
+            ctx.addr = Some(source);
+        }
+        if let Some(destination) = header.destination {
+            ctx.local_addr = Some(destination);
+        }
+        if header.ssl.is_some_and(|x| x & PP2_CLIENT_SSL != 0) {
+            ctx.secure = true;
+        }
+        if header.alpn.is_some() {
+            ctx.alpn = header.alpn;
+        }
+        if header.authority.is_some() {
+            ctx.server_name = header.authority;
+        }
+
+        let stream = match rest.is_empty() {
+            true => stream,
+            false => ProxyStream::new_prefixed(rest, stream)
+        };
+        self.handler.handle(stream, ctx).await
+    }
+
+    fn alpn_protocols(&self) -> Option<Vec<String>> {
+        self.handler.alpn_protocols()
+    }
+}
+
+/// Read a PROXY protocol v1 or v2 header, returning it with the bytes read past it
+pub async fn read_header<T: AsyncRead + Unpin>(stream: &mut T) -> io::Result<(ProxyHeader, Vec<u8>)> {
+    let mut buf = Vec::new();
+    loop {
+        if let Some((header, length)) = parse_header(&buf)? {
+            buf.drain(..length);
+            return Ok((header, buf));
+        }
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "Truncated PROXY protocol header"));
+        }
+    }
+}
+
+/// Parse the header at the start of `buf` and its length, None when more bytes are needed
+fn parse_header(buf: &[u8]) -> io::Result<Option<(ProxyHeader, usize)>> {
+    if buf.starts_with(V1_PREFIX) {
+        let line = &buf[..buf.len().min(V1_MAX_LENGTH)];
+        return match line.windows(2).position(|x| x == b"\r\n") {
+            Some(end) => Ok(Some((parse_v1(&line[..end])?, end + 2))),
+            None if line.len() == V1_MAX_LENGTH => Err(invalid("PROXY protocol v1 header too long")),
+            None => Ok(None)
+        };
+    }
+
+    if buf.starts_with(V2_SIGNATURE) {
+        if buf.len() < V2_SIGNATURE.len() + 4 {
+            return Ok(None);
+        }
+        let (version, command) = (buf[12] >> 4, buf[12] & 0x0f);
+        if version != 2 {
+            return Err(invalid("Unsupported PROXY protocol version"));
+        }
+        let length = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+        return match buf.get(16..16 + length) {
+            Some(payload) => Ok(Some((parse_v2(command, buf[13], payload)?, 16 + length))),
+            None => Ok(None)
+        };
+    }
+
+    // Wait for enough bytes to tell whether either signature is there
+    match V1_PREFIX.starts_with(buf) || V2_SIGNATURE.starts_with(buf) {
+        true => Ok(None),
+        false => Err(invalid("Missing PROXY protocol header"))
+    }
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<ProxyHeader> {
+    let line = str::from_utf8(line).map_err(|_| invalid("Invalid PROXY protocol v1 header"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(ProxyHeader::default()),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            let source = IpAddr::from_str(source).map_err(|_| invalid("Invalid PROXY protocol source address"))?;
+            let destination = IpAddr::from_str(destination).map_err(|_| invalid("Invalid PROXY protocol destination address"))?;
+            if source.is_ipv4() != (protocol == "TCP4") || destination.is_ipv4() != (protocol == "TCP4") {
+                return Err(invalid("PROXY protocol address family mismatch"));
+            }
+
+            Ok(ProxyHeader {
+                source: Some(SocketAddr::new(source, parse_port(source_port)?)),
+                destination: Some(SocketAddr::new(destination, parse_port(destination_port)?)),
+                ..Default::default()
+            })
+        },
+        _ => Err(invalid("Invalid PROXY protocol v1 header"))
+    }
+}
+
+fn parse_v2(command: u8, family: u8, payload: &[u8]) -> io::Result<ProxyHeader> {
+    let (mut header, tlvs) = match (command, family) {
+        // Health checks from the proxy itself carry no client information
+        (PP2_CMD_LOCAL, _) => return Ok(ProxyHeader::default()),
+        (PP2_CMD_PROXY, PP2_FAM_TCP4 | PP2_FAM_UDP4) if payload.len() >= 12 => {
+            let source = Ipv4Addr::from(<[u8; 4]>::try_from(&payload[0..4]).unwrap());
+            let destination = Ipv4Addr::from(<[u8; 4]>::try_from(&payload[4..8]).unwrap());
+            (ProxyHeader {
+                source: Some(SocketAddr::new(source.into(), u16::from_be_bytes([payload[8], payload[9]]))),
+                destination: Some(SocketAddr::new(destination.into(), u16::from_be_bytes([payload[10], payload[11]]))),
+                ..Default::default()
+            }, &payload[12..])
+        },
+        (PP2_CMD_PROXY, PP2_FAM_TCP6 | PP2_FAM_UDP6) if payload.len() >= 36 => {
+            let source = Ipv6Addr::from(<[u8; 16]>::try_from(&payload[0..16]).unwrap());
+            let destination = Ipv6Addr::from(<[u8; 16]>::try_from(&payload[16..32]).unwrap());
+            (ProxyHeader {
+                source: Some(SocketAddr::new(source.into(), u16::from_be_bytes([payload[32], payload[33]]))),
+                destination: Some(SocketAddr::new(destination.into(), u16::from_be_bytes([payload[34], payload[35]]))),
+                ..Default::default()
+            }, &payload[36..])
+        },
+        // Unix and unspecified families don't map to an IP address, the TLVs are still useful
+        (PP2_CMD_PROXY, PP2_FAM_UNSPEC) => (ProxyHeader::default(), payload),
+        (PP2_CMD_PROXY, PP2_FAM_UNIX_STREAM | PP2_FAM_UNIX_DGRAM) if payload.len() >= 216 => (ProxyHeader::default(), &payload[216..]),
+        _ => return Err(invalid("Invalid PROXY protocol v2 header"))
+    };
+
+    for (kind, value) in parse_tlvs(tlvs)? {
+        match kind {
+            PP2_TYPE_ALPN => header.alpn = Some(parse_string(value)?),
+            PP2_TYPE_AUTHORITY => header.authority = Some(parse_string(value)?),
+            PP2_TYPE_SSL if value.len() >= 5 => header.ssl = Some(value[0]),
+            PP2_TYPE_SSL => return Err(invalid("Invalid PROXY protocol SSL TLV")),
+            _ => ()
+        }
+    }
+
+    Ok(header)
+}
+
+fn parse_tlvs(mut data: &[u8]) -> io::Result<Vec<(u8, &[u8])>> {
+    let mut tlvs = Vec::new();
+    while !data.is_empty() {
+        if data.len() < 3 {
+            return Err(invalid("Truncated PROXY protocol TLV"));
+        }
+        let length = u16::from_be_bytes([data[1], data[2]]) as usize;
+        let value = data.get(3..3 + length).ok_or(invalid("Truncated PROXY protocol TLV"))?;
+        tlvs.push((data[0], value));
+        data = &data[3 + length..];
+    }
+
+    Ok(tlvs)
+}
+
+fn push_tlv(payload: &mut Vec<u8>, kind: u8, value: &[u8]) {
+    payload.push(kind);
+    payload.extend((value.len() as u16).to_be_bytes());
+    payload.extend(value);
+}
+
+fn to_ipv6_addr(addr: IpAddr) -> Ipv6Addr {
+    match addr {
+        IpAddr::V4(addr) => addr.to_ipv6_mapped(),
+        IpAddr::V6(addr) => addr
+    }
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(to_ipv6_addr(addr.ip()).into(), addr.port())
+}
+
+fn parse_string(value: &[u8]) -> io::Result<String> {
+    String::from_utf8(value.to_vec()).map_err(|_| invalid("Invalid PROXY protocol TLV value"))
+}
+
+fn parse_port(port: &str) -> io::Result<u16> {
+    // Leading zeroes aren't allowed by the specification
+    if port.len() > 1 && port.starts_with('0') {
+        return Err(invalid("Invalid PROXY protocol port"));
+    }
+    port.parse().map_err(|_| invalid("Invalid PROXY protocol port"))
+}
+
+fn parse_net(net: &str) -> Result<IpNet, ipnet::AddrParseError> {
+    net.parse().or_else(|e| IpAddr::from_str(net).map(IpNet::from).map_err(|_| e))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    use super::*;
+
+    fn v2(command: u8, family: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend((payload.len() as u16).to_be_bytes());
+        header.extend(payload);
+        header
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4() {
+        let (header, rest) = read_header(&mut &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /"[..]).await.unwrap();
+        assert_eq!(header.source, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(header.destination, Some("198.51.100.1:443".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn v1_tcp6() {
+        let (header, rest) = read_header(&mut &b"PROXY TCP6 2001:db8::1 2001:db8::2 65535 80\r\n"[..]).await.unwrap();
+        assert_eq!(header.source, Some("[2001:db8::1]:65535".parse().unwrap()));
+        assert_eq!(header.destination, Some("[2001:db8::2]:80".parse().unwrap()));
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn v1_unknown() {
+        let (header, length) = parse_header(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\nx").unwrap().unwrap();
+        assert_eq!(header.source, None);
+        assert_eq!(length, 35);
+    }
+
+    #[test]
+    fn v1_invalid() {
+        for line in [
+            &b"PROXY TCP4 192.0.2.1 2001:db8::2 1 2\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 01 2\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 1 65536\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 1\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 1 2\r\n"
+        ] {
+            assert_eq!(parse_header(line).unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn v1_truncated() {
+        assert_eq!(parse_header(b"PRO").unwrap().map(|x| x.1), None);
+        assert_eq!(parse_header(b"PROXY TCP4 192.0.2.1").unwrap().map(|x| x.1), None);
+        let e = read_header(&mut &b"PROXY TCP4 192.0.2.1 198.51.100.1 1 2\r"[..]).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn v1_too_long() {
+        let line = format!("PROXY UNKNOWN {}\r\n", "x".repeat(V1_MAX_LENGTH));
+        assert_eq!(parse_header(line.as_bytes()).unwrap_err().kind(), ErrorKind::InvalidData);
+        // The longest allowed line is accepted
+        let line = format!("PROXY UNKNOWN {}\r\n", "x".repeat(V1_MAX_LENGTH - 16));
+        assert_eq!(parse_header(line.as_bytes()).unwrap().map(|x| x.1), Some(V1_MAX_LENGTH));
+    }
+
+    #[test]
+    fn missing_header() {
+        assert_eq!(parse_header(b"GET / HTTP/1.1\r\n").unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(parse_header(b"\r\n\r\n\0\r\nQUIZ").unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn v2_tcp4_with_tlvs() {
+        let mut payload = vec![192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        push_tlv(&mut payload, PP2_TYPE_ALPN, b"h2");
+        push_tlv(&mut payload, PP2_TYPE_AUTHORITY, b"example.com");
+        push_tlv(&mut payload, PP2_TYPE_SSL, &[PP2_CLIENT_SSL, 0, 0, 0, 0]);
+        // Unknown TLVs are skipped
+        push_tlv(&mut payload, 0xe0, b"custom");
+        let mut data = v2(PP2_CMD_PROXY, PP2_FAM_TCP4, &payload);
+        data.extend(b"rest");
+
+        let mut stream = data.as_slice();
+        let (header, mut rest) = read_header(&mut stream).await.unwrap();
+        // Whatever wasn't read along with the header is still in the stream
+        rest.extend(stream);
+        assert_eq!(header.source, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(header.destination, Some("198.51.100.1:443".parse().unwrap()));
+        assert_eq!(header.alpn.as_deref(), Some("h2"));
+        assert_eq!(header.authority.as_deref(), Some("example.com"));
+        assert_eq!(header.ssl, Some(PP2_CLIENT_SSL));
+        assert_eq!(rest, b"rest");
+    }
+
+    #[test]
+    fn v2_tcp6() {
+        let mut payload = Vec::new();
+        payload.extend("2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        payload.extend("2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        payload.extend([0, 1, 0, 2]);
+        let (header, _) = parse_header(&v2(PP2_CMD_PROXY, PP2_FAM_TCP6, &payload)).unwrap().unwrap();
+        assert_eq!(header.source, Some("[2001:db8::1]:1".parse().unwrap()));
+        assert_eq!(header.destination, Some("[2001:db8::2]:2".parse().unwrap()));
+    }
+
+    #[test]
+    fn v2_local() {
+        let data = v2(PP2_CMD_LOCAL, PP2_FAM_TCP4, &[192, 0, 2, 1, 198, 51, 100, 1, 0, 1, 0, 2]);
+        let (header, length) = parse_header(&data).unwrap().unwrap();
+        assert_eq!(header.source, None);
+        assert_eq!(length, data.len());
+    }
+
+    #[test]
+    fn v2_truncated() {
+        let data = v2(PP2_CMD_PROXY, PP2_FAM_TCP4, &[192, 0, 2, 1, 198, 51, 100, 1, 0, 1, 0, 2]);
+        for length in [1, 12, 15, data.len() - 1] {
+            assert_eq!(parse_header(&data[..length]).unwrap().map(|x| x.1), None);
+        }
+
+        // Addresses or TLVs cut short inside the declared length
+        let data = v2(PP2_CMD_PROXY, PP2_FAM_TCP4, &[192, 0, 2, 1]);
+        assert_eq!(parse_header(&data).unwrap_err().kind(), ErrorKind::InvalidData);
+        let data = v2(PP2_CMD_PROXY, PP2_FAM_UNSPEC, &[PP2_TYPE_ALPN, 0, 5, b'h']);
+        assert_eq!(parse_header(&data).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn v2_invalid() {
+        let mut data = v2(PP2_CMD_PROXY, PP2_FAM_UNSPEC, &[]);
+        data[12] = 0x11;
+        assert_eq!(parse_header(&data).unwrap_err().kind(), ErrorKind::InvalidData);
+        let data = v2(0x2, PP2_FAM_UNSPEC, &[]);
+        assert_eq!(parse_header(&data).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    fn round_trip(header: &ProxyHeader, version: ProxyProtocolVersion) -> ProxyHeader {
+        let data = header.encode(version);
+        let (decoded, length) = parse_header(&data).unwrap().unwrap();
+        assert_eq!(length, data.len());
+        decoded
+    }
+
+    #[test]
+    fn round_trip_addresses() {
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            for (source, destination) in [
+                ("192.0.2.1:56324", "198.51.100.1:443"),
+                ("[2001:db8::1]:1", "[2001:db8::2]:65535")
+            ] {
+                let header = ProxyHeader {
+                    source: Some(source.parse().unwrap()),
+                    destination: Some(destination.parse().unwrap()),
+                    ..Default::default()
+                };
+                let decoded = round_trip(&header, version);
+                assert_eq!(decoded.source, header.source);
+                assert_eq!(decoded.destination, header.destination);
+            }
+
+            // Mixed families are mapped to IPv6
+            let header = ProxyHeader {
+                source: Some("192.0.2.1:1".parse().unwrap()),
+                destination: Some("[2001:db8::2]:2".parse().unwrap()),
+                ..Default::default()
+            };
+            let decoded = round_trip(&header, version);
+            assert_eq!(decoded.source, Some("[::ffff:192.0.2.1]:1".parse().unwrap()));
+            assert_eq!(decoded.destination, header.destination);
+        }
+    }
+
+    #[test]
+    fn round_trip_tlvs() {
+        let header = ProxyHeader {
+            source: Some("192.0.2.1:1".parse().unwrap()),
+            destination: Some("198.51.100.1:2".parse().unwrap()),
+            alpn: Some("http/1.1".to_string()),
+            authority: Some("example.com".to_string()),
+            ssl: Some(PP2_CLIENT_SSL)
+        };
+        let decoded = round_trip(&header, ProxyProtocolVersion::V2);
+        assert_eq!(decoded.alpn, header.alpn);
+        assert_eq!(decoded.authority, header.authority);
+        assert_eq!(decoded.ssl, header.ssl);
+    }
+
+    #[test]
+    fn round_trip_without_addresses() {
+        assert_eq!(ProxyHeader::default().encode(ProxyProtocolVersion::V1), b"PROXY UNKNOWN\r\n");
+
+        let data = ProxyHeader::default().encode(ProxyProtocolVersion::V2);
+        assert_eq!(data[12], 0x20 | PP2_CMD_LOCAL);
+        assert_eq!(data[13], PP2_FAM_UNSPEC);
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            let decoded = round_trip(&ProxyHeader::default(), version);
+            assert_eq!(decoded.source, None);
+            assert_eq!(decoded.destination, None);
+        }
+    }
+
+    /// Reports the client address it was handed
+    struct Peer;
+
+    #[async_trait]
+    impl Handler for Peer {
+        async fn handle(&self, mut stream: ProxyStream, ctx: Context) -> Result<(), Box<dyn Error>> {
+            stream.write_all(ctx.addr.map(|x| x.to_string()).unwrap_or_default().as_bytes()).await?;
+            Ok(())
+        }
+    }
+
+    async fn handle(trust_unix: bool, addr: Option<&str>) -> Result<String, String> {
+        let (mut client, server) = duplex(256);
+        client.write_all(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n").await.unwrap();
+        let handler = ProxyProtocolHandler::new(&["10.0.0.0/8".to_string()], trust_unix, Box::new(Peer), None).unwrap();
+        let ctx = Context {
+            addr: addr.map(|x| x.parse().unwrap()),
+            ..Default::default()
+        };
+        handler.handle(ProxyStream::new_dynamic(Box::pin(server)), ctx).await.map_err(|e| e.to_string())?;
+
+        let mut peer = String::new();
+        client.read_to_string(&mut peer).await.unwrap();
+        Ok(peer)
+    }
+
+    #[tokio::test]
+    async fn trusted_senders() {
+        assert_eq!(handle(false, Some("10.1.2.3:4000")).await.unwrap(), "192.0.2.1:56324");
+        assert!(handle(true, Some("192.0.2.9:4000")).await.unwrap_err().contains("Untrusted"));
+
+        // Unix socket peers have no address and are only accepted when configured
+        assert_eq!(handle(true, None).await.unwrap(), "192.0.2.1:56324");
+        assert!(handle(false, None).await.unwrap_err().contains("trust_unix"));
+    }
+}
@@ -0,0 +1,138 @@
+use futures::future::try_join_all;
+
+use tokio::task::JoinHandle;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::activation::ListenFds;
+use crate::error::Error;
+use crate::handler::SendableHandler;
+use crate::listener::Listener;
+use crate::listener::SocketPermissions;
+use crate::settings::{build_handler, build_listener, build_permissions, Build, Settings};
+use crate::shutdown::Shutdown;
+use crate::tls::acme::AcmeRenewals;
+use crate::tls::sessions::Sessions;
+
+/// Build the complete handler tree without binding sockets, starting tasks or writing files
+pub async fn check(settings: &Settings) -> Result<(), Error> {
+    let mut keys = HashSet::new();
+    for config in &settings.servers {
+        if !keys.insert(config.key()) {
+            return Err(format!("Duplicate listener {}", config.key()).into());
+        }
+        build_permissions(config)?;
+    }
+
+    let sessions = Sessions::new(settings.tls_sessions.as_ref(), None)?;
+    try_join_all(settings.servers.iter().map(|x| build_handler(x.handler(), Build::Check(&sessions)))).await?;
+    Ok(())
+}
+
+struct RunningListener {
+    listener: Arc<dyn Listener + Send + Sync>,
+    task: JoinHandle<()>
+}
+
+enum Change {
+    Update(SendableHandler, Option<SocketPermissions>),
+    Add(Box<dyn Listener + Send + Sync>)
+}
+
+/// The running listeners, keyed by the socket they are bound to
+pub struct Server {
+    listeners: HashMap<String, RunningListener>,
+    listen_fds: ListenFds,
+    renewals: AcmeRenewals,
+    sessions: Option<Sessions>,
+    shutdown: Shutdown
+}
+
+impl Server {
+    pub fn new(shutdown: Shutdown, listen_fds: ListenFds) -> Self {
+        Self {
+            listeners: HashMap::new(),
+            listen_fds,
+            renewals: AcmeRenewals::default(),
+            sessions: None,
+            shutdown
+        }
+    }
+
+    /// Apply the settings completely, or not at all when any handler or new listener fails to build
+    pub async fn load(&mut self, settings: &Settings) -> Result<(), Error> {
+        let (changes, sessions) = match self.build(settings).await {
+            Ok(built) => built,
+            Err(e) => {
+                self.renewals.discard();
+                return Err(e);
+            }
+        };
+        self.renewals.commit();
+        self.sessions = Some(sessions);
+
+        // Stop accepting on sockets which are no longer configured, their connections finish on the old handlers
+        self.listeners.retain(|key, running| {
+            let keep = changes.contains_key(key);
+            if !keep {
+                running.task.abort();
+            }
+            keep
+        });
+
+        for (key, change) in changes {
+            match change {
+                Change::Update(handler, permissions) => {
+                    let listener = &self.listeners[&key].listener;
+                    listener.set_handler(handler);
+                    // The socket file stays in place, so changed permissions are applied to it directly
+                    if let Some(Err(e)) = permissions.map(|x| listener.set_permissions(&x)) {
+                        eprintln!("Failed to update permissions of {}: {}", key, e);
+                    }
+                },
+                Change::Add(listener) => {
+                    let running = self.spawn(listener.into());
+                    self.listeners.insert(key, running);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Build the handlers and new listeners without touching the running ones
+    async fn build(&self, settings: &Settings) -> Result<(HashMap<String, Change>, Sessions), Error> {
+        // Running handlers keep their session settings, the cache is shared with them unless its size changed
+        let sessions = Sessions::new(settings.tls_sessions.as_ref(), self.sessions.as_ref())?;
+        let handlers = try_join_all(settings.servers.iter().map(|x| build_handler(x.handler(), Build::Run(&self.renewals, &sessions)))).await?;
+
+        let mut changes = HashMap::new();
+        for (config, handler) in settings.servers.iter().zip(handlers) {
+            let key = config.key();
+            if changes.contains_key(&key) {
+                return Err(format!("Duplicate listener {}", key).into());
+            }
+
+            let change = match self.listeners.contains_key(&key) {
+                true => Change::Update(handler, build_permissions(config)?),
+                false => Change::Add(build_listener(config, handler, &self.listen_fds).await?)
+            };
+            changes.insert(key, change);
+        }
+        Ok((changes, sessions))
+    }
+
+    fn spawn(&self, listener: Arc<dyn Listener + Send + Sync>) -> RunningListener {
+        let shutdown = self.shutdown.clone();
+        let task = tokio::spawn({
+            let listener = listener.clone();
+            async move { listener.handle(&shutdown).await }
+        });
+
+        RunningListener {
+            listener,
+            task
+        }
+    }
+}
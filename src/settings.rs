@@ -2,55 +2,185 @@ use async_recursion::async_recursion;
 
 use config::{Config, ConfigError, File};
 
-use futures::future::{join_all, try_join_all};
-use serde_derive::Deserialize;
+use futures::future::try_join_all;
+use serde::de::{self, Deserializer};
+use serde::Serializer;
+use serde_derive::{Deserialize, Serialize};
 
-use std::path::PathBuf;
+use tokio::task::JoinHandle;
+
+use std::future::Future;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+use crate::activation::ListenFds;
+use crate::address::Address;
+use crate::balancer::Balancer;
+use crate::detect::{DetectHandler, Protocol};
 use crate::error::Error;
 use crate::handler::{self};
 use crate::listener::{self, TcpListener};
-use crate::http::{self, AuthenticatorService, FileService, HelloService, Http1Handler, Http2Handler, HttpHandler, LogLayer, ProxyService, RouterService};
-use crate::tls::{self, TlsHandler, LazyTlsHandler};
+use crate::http::{self, AuthenticatorService, ClientCertificateLayer, FileService, FingerprintLayer, HelloService, Http1Handler, Http2Handler, HttpHandler, LogLayer, MetricsService, ProxyService, RouterService, TlsHeadersLayer};
+use crate::proxy_protocol::ProxyProtocolHandler;
+use crate::starttls::StartTlsHandler;
+use crate::tls::acme::AcmeRenewals;
+use crate::tls::sessions::Sessions;
+use crate::tls::{self, AlpnHandler, TlsHandler, LazyTlsHandler, PassthroughHandler};
 use crate::tunnel::TunnelHandler;
 
-#[derive(Debug, Deserialize)]
+/// How handlers are built, either to serve connections or only to check the settings
+#[derive(Clone, Copy)]
+pub enum Build<'a> {
+    // Certificate renewals are shared with the handlers of the running server, sessions with the handlers of the new configuration
+    Run(&'a AcmeRenewals, &'a Sessions),
+    // Nothing is started or written, so checking doesn't affect a running instance
+    Check(&'a Sessions)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Settings {
-    pub servers: Vec<Listener>
+    pub servers: Vec<Listener>,
+    pub drain_timeout: Option<u64>,
+    pub tls_sessions: Option<TlsSessions>
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TlsSessions {
+    pub cache_size: Option<usize>,
+    pub tickets: Option<bool>,
+    pub ticket_key: Option<PathBuf>,
+    pub ticket_rotation: Option<u64>
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum Listener {
-    Socket(SocketListener)
+    Socket(SocketListener),
+    Unix(UnixListener)
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct SocketListener {
     pub listen: String,
+    pub fd_name: Option<String>,
     pub handler: Handler
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UnixListener {
+    pub path: String,
+    pub mode: Option<String>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    pub remove_stale: Option<bool>,
+    pub fd_name: Option<String>,
+    pub handler: Handler
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct SniHandler {
     pub hostname: String,
-    pub certificate: String,
-    pub key: String,
+    pub certificate: Option<String>,
+    pub key: Option<String>,
+    pub ocsp_response: Option<String>,
+    pub certificates: Option<Vec<CertificatePair>>,
+    pub acme: Option<Acme>,
+    pub ocsp: Option<Ocsp>,
+    pub client_auth: Option<ClientAuth>,
+    #[serde(flatten)]
+    pub protocols: TlsProtocols,
     pub handler: Box<Handler>,
     pub ktls: Option<bool>
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Tls {
-    pub certificate: String,
-    pub key: String,
+    pub certificate: Option<String>,
+    pub key: Option<String>,
+    pub ocsp_response: Option<String>,
+    pub certificates: Option<Vec<CertificatePair>>,
+    pub acme: Option<Acme>,
+    pub ocsp: Option<Ocsp>,
+    pub client_auth: Option<ClientAuth>,
+    #[serde(flatten)]
+    pub protocols: TlsProtocols,
     pub handler: Box<Handler>,
     pub ktls: Option<bool>,
+    #[serde(flatten)]
+    pub limits: HandshakeLimits,
     pub sni: Vec<SniHandler>
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CertificatePair {
+    pub certificate: String,
+    pub key: String,
+    pub ocsp_response: Option<String>
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Ocsp {
+    pub cache_dir: PathBuf,
+    pub responder: Option<String>
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HandshakeLimits {
+    pub handshake_timeout: Option<u64>,
+    pub max_client_hello: Option<usize>
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TlsProtocols {
+    pub min_version: Option<TlsVersion>,
+    pub max_version: Option<TlsVersion>,
+    pub cipher_suites: Option<Vec<String>>,
+    pub kx_groups: Option<Vec<String>>
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, PartialOrd)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    V1_2,
+    #[serde(rename = "1.3")]
+    V1_3
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ClientAuth {
+    pub ca: String,
+    pub mode: Option<ClientAuthMode>,
+    pub crl: Option<Vec<String>>
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuthMode {
+    Required,
+    Optional
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Acme {
+    pub directory: Option<String>,
+    pub contact: Option<Vec<String>>,
+    pub domains: Vec<String>,
+    pub state_dir: PathBuf,
+    pub challenge: Option<AcmeChallenge>,
+    pub ca: Option<PathBuf>,
+    pub renew_before: Option<u64>,
+    pub terms_of_service_agreed: Option<bool>
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum AcmeChallenge {
+    #[serde(rename = "http-01")]
+    Http01,
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum Handler {
     Http(Http),
@@ -58,124 +188,443 @@ pub enum Handler {
     Http2(Http),
     Tunnel(Tunnel),
     Tls(Tls),
-    LazyTls(Tls)
+    LazyTls(Tls),
+    Passthrough(Passthrough),
+    StartTls(StartTls),
+    Detect(Detect),
+    Alpn(Alpn),
+    ProxyProtocol(ProxyProtocol)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Passthrough {
+    pub sni: Vec<PassthroughRoute>,
+    pub handler: Option<Box<Handler>>,
+    #[serde(flatten)]
+    pub limits: HandshakeLimits
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PassthroughRoute {
+    pub hostname: String,
+    pub handler: Box<Handler>
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StartTls {
+    pub protocol: StartTlsProtocol,
+    pub hostname: Option<String>,
+    pub timeout: Option<u64>,
+    pub handler: Box<Handler>
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StartTlsProtocol {
+    Smtp,
+    Imap,
+    Pop3,
+    Postgres
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Detect {
+    pub tls: Option<Box<Handler>>,
+    pub http: Option<Box<Handler>>,
+    pub http2: Option<Box<Handler>>,
+    pub ssh: Option<Box<Handler>>,
+    pub proxy_protocol: Option<Box<Handler>>,
+    pub fallback: Option<Box<Handler>>,
+    pub timeout: Option<u64>
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Alpn {
+    pub routes: Vec<AlpnRoute>,
+    pub fallback: Option<Box<Handler>>
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AlpnRoute {
+    pub protocols: Option<Vec<String>>,
+    pub handler: Box<Handler>
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Tunnel {
-    pub target: String
+    pub target: Option<String>,
+    pub targets: Option<Vec<String>>,
+    pub balance: Option<Balance>,
+    pub connect_timeout: Option<u64>,
+    pub max_fails: Option<u32>,
+    pub fail_timeout: Option<u64>,
+    pub health_check: Option<HealthCheck>,
+    pub nameservers: Option<Vec<String>>,
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    pub tls: Option<TunnelTls>
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    RoundRobin,
+    LeastConnections,
+    RandomTwo,
+    IpHash
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HealthCheck {
+    pub interval: Option<u64>,
+    pub timeout: Option<u64>,
+    pub healthy_threshold: Option<u32>,
+    pub unhealthy_threshold: Option<u32>
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TunnelTls {
+    pub sni: Option<String>,
+    pub ca: Option<String>,
+    pub certificate: Option<String>,
+    pub key: Option<String>,
+    pub alpn: Option<Vec<String>>
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ProxyProtocol {
+    pub trusted: Vec<String>,
+    pub trust_unix: Option<bool>,
+    pub timeout: Option<u64>,
+    pub handler: Box<Handler>
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Http {
     pub service: Service,
     pub layers: Option<Vec<Layer>>
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum Layer {
     Log(Log),
-    Authenticator(Authenticator)
+    Authenticator(Authenticator),
+    ClientCertificate(ClientCertificate),
+    TlsHeaders(TlsHeaders),
+    Fingerprints(Fingerprints)
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Log {
     pub path: PathBuf,
+    pub fingerprints: Option<bool>
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum Service {
     Hello,
+    Metrics,
     Proxy(Proxy),
     File(Files),
     Router(Router)
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Authenticator {
     pub discovery_url: String,
     pub client_id: String,
+    #[serde(serialize_with = "redact")]
     pub client_secret: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ClientCertificate {
+    pub subjects: Option<Vec<String>>,
+    pub sans: Option<Vec<String>>,
+    pub fingerprints: Option<Vec<String>>
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Fingerprints {
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TlsHeaders {
+    pub subject: Option<String>,
+    pub certificate: Option<String>,
+    pub fingerprint: Option<String>,
+    pub version: Option<String>,
+    pub cipher: Option<String>,
+    pub server_name: Option<String>,
+    pub alpn: Option<String>,
+    pub ja3: Option<String>,
+    pub ja4: Option<String>,
+    pub offered_alpn: Option<String>,
+    pub offered_ciphers: Option<String>
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Proxy {
-    pub uri: String
+    pub uri: String,
+    pub proxy_protocol: Option<ProxyProtocolVersion>
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Files {
     pub path: String
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Router {
     pub routes: Vec<Route>
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Route {
     pub path: String,
+    pub fingerprints: Option<Vec<String>>,
     pub service: Service
 }
 
+// Versions are written as plain numbers in YAML, so they arrive either as float or string
+impl<'de> de::Deserialize<'de> for TlsVersion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct VersionVisitor;
+
+        impl de::Visitor<'_> for VersionVisitor {
+            type Value = TlsVersion;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "TLS version 1.2 or 1.3")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                match v {
+                    "1.2" => Ok(TlsVersion::V1_2),
+                    "1.3" => Ok(TlsVersion::V1_3),
+                    _ => Err(E::invalid_value(de::Unexpected::Str(v), &self))
+                }
+            }
+
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+                self.visit_str(&v.to_string())
+            }
+        }
+
+        deserializer.deserialize_any(VersionVisitor)
+    }
+}
+
+impl Handler {
+    /// Whether a layer or route of the connection uses the TLS fingerprint, which is only computed when needed.
+    /// Nested TLS handlers fingerprint their own handshake.
+    pub fn uses_fingerprints(&self) -> bool {
+        match self {
+            Handler::Http(s) | Handler::Http1(s) | Handler::Http2(s) => s.service.uses_fingerprints()
+                || s.layers.iter().flatten().any(Layer::uses_fingerprints),
+            Handler::Detect(s) => [&s.tls, &s.http, &s.http2, &s.ssh, &s.proxy_protocol, &s.fallback].into_iter()
+                .flatten()
+                .any(|x| x.uses_fingerprints()),
+            Handler::Alpn(s) => s.routes.iter().any(|x| x.handler.uses_fingerprints())
+                || s.fallback.as_ref().is_some_and(|x| x.uses_fingerprints()),
+            Handler::ProxyProtocol(s) => s.handler.uses_fingerprints(),
+            Handler::Tunnel(_) | Handler::Tls(_) | Handler::LazyTls(_) | Handler::Passthrough(_) | Handler::StartTls(_) => false
+        }
+    }
+}
+
+impl Service {
+    fn uses_fingerprints(&self) -> bool {
+        match self {
+            Service::Router(s) => s.routes.iter().any(|x| x.fingerprints.is_some() || x.service.uses_fingerprints()),
+            _ => false
+        }
+    }
+}
+
+impl Layer {
+    fn uses_fingerprints(&self) -> bool {
+        match self {
+            Layer::Log(s) => s.fingerprints.unwrap_or(false),
+            Layer::TlsHeaders(s) => s.ja3.is_some() || s.ja4.is_some() || s.offered_alpn.is_some() || s.offered_ciphers.is_some(),
+            Layer::Fingerprints(_) => true,
+            Layer::Authenticator(_) | Layer::ClientCertificate(_) => false
+        }
+    }
+}
+
+impl Build<'_> {
+    /// Spawn a background task of a handler, unless only checking
+    pub fn spawn<F>(self, task: F) -> Option<JoinHandle<()>>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        match self {
+            Build::Run(..) => Some(tokio::spawn(task)),
+            Build::Check(_) => None
+        }
+    }
+}
+
+impl<'a> Build<'a> {
+    pub fn sessions(self) -> &'a Sessions {
+        match self {
+            Build::Run(_, sessions) | Build::Check(sessions) => sessions
+        }
+    }
+}
+
+// Secrets are left out of --dump
+fn redact<T, S: Serializer>(_: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str("<redacted>")
+}
+
 impl Settings {
-    pub fn new() -> Result<Self, ConfigError> {
+    pub fn new(path: &Path) -> Result<Self, ConfigError> {
         let s = Config::builder()
-            .add_source(File::with_name("config.yaml"))
+            .add_source(File::from(path))
             .build()?;
 
         s.try_deserialize()
     }
 }
 
-pub async fn build_listener(listener: &Listener) -> Result<Box<dyn listener::Listener>, Error> {
+impl Listener {
+    pub fn handler(&self) -> &Handler {
+        match self {
+            Listener::Socket(s) => &s.handler,
+            Listener::Unix(s) => &s.handler
+        }
+    }
+
+    /// Identifies the bound socket, listeners with the same key are kept open on reload.
+    /// Settings which only apply when binding, like `remove_stale`, take effect once the socket is bound anew
+    pub fn key(&self) -> String {
+        match self {
+            Listener::Socket(s) => format!("socket:{}:{}", s.listen, s.fd_name.as_deref().unwrap_or("")),
+            Listener::Unix(s) => format!("unix:{}:{}", s.path, s.fd_name.as_deref().unwrap_or(""))
+        }
+    }
+}
+
+/// Mode and ownership of the socket file of a Unix listener, sockets passed by the service manager or in the abstract namespace have none
+pub fn build_permissions(listener: &Listener) -> Result<Option<listener::SocketPermissions>, Error> {
+    Ok(match listener {
+        Listener::Unix(s) if s.fd_name.is_none() && !s.path.starts_with('@') => Some(listener::SocketPermissions::new(s)?),
+        _ => None
+    })
+}
+
+pub async fn build_listener(listener: &Listener, handler: handler::SendableHandler, listen_fds: &ListenFds) -> Result<Box<dyn listener::Listener + Send + Sync>, Error> {
     Ok(match listener {
-        Listener::Socket(s) => Box::new(TcpListener::new(&s.listen, build_handler(&s.handler).await?).await?)
+        Listener::Socket(s) => Box::new(TcpListener::new(s, handler, listen_fds).await?),
+        Listener::Unix(s) => Box::new(listener::UnixListener::new(s, handler, listen_fds)?)
     })
 }
 
 #[async_recursion]
-pub async fn build_handler(handler: &Handler) -> Result<Box<dyn handler::Handler + Send + Sync + Unpin>, Error> {
+pub async fn build_handler<'a>(handler: &'a Handler, build: Build<'a>) -> Result<Box<dyn handler::Handler + Send + Sync + Unpin>, Error> {
     let handler: Box<dyn handler::Handler + Send + Sync + Unpin> = match handler {
-        Handler::Tunnel(s) => Box::new(TunnelHandler::new(s.target.clone())),
-        Handler::Tls(s) => Box::new(TlsHandler::new(s, build_handler(&s.handler).await?)?),
-        Handler::LazyTls(s) => Box::new(LazyTlsHandler::new(s, build_handler(&s.handler).await?, try_join_all(s.sni.iter().map(|x| async {
-            Ok::<tls::SniHandler, Error>(tls::SniHandler::new(&x.hostname, build_handler(&x.handler).await?, &x.certificate, &x.key)?)
-        })).await?)?),
-        Handler::Http(s) => Box::new(HttpHandler::new(build_service(&s.service, s.layers.as_ref()).await?)),
-        Handler::Http1(s) => Box::new(Http1Handler::new(build_service(&s.service, s.layers.as_ref()).await?)),
-        Handler::Http2(s) => Box::new(Http2Handler::new(build_service(&s.service, s.layers.as_ref()).await?))
+        Handler::Tunnel(s) => {
+            let targets = s.target.iter().chain(s.targets.iter().flatten())
+                .map(|x| Address::parse(x, s.nameservers.as_deref()))
+                .collect::<Result<Vec<_>, _>>()?;
+            if targets.is_empty() {
+                return Err("Tunnel needs a target or targets".into());
+            }
+            let tls = s.tls.as_ref().map(|x| tls::TlsClient::new(x, build)).transpose()?;
+            Box::new(TunnelHandler::new(Balancer::new(targets, s, build), s.proxy_protocol, tls))
+        },
+        Handler::Tls(s) => Box::new(TlsHandler::new(s, build_handler(&s.handler, build).await?, build)?),
+        Handler::LazyTls(s) => Box::new(LazyTlsHandler::new(s, build_handler(&s.handler, build).await?, try_join_all(s.sni.iter().map(|x| async {
+            Ok::<_, Error>(tls::SniHandler::new(x, build_handler(&x.handler, build).await?, build)?)
+        })).await?, build)?),
+        Handler::Passthrough(s) => Box::new(PassthroughHandler::new(try_join_all(s.sni.iter().map(|x| async {
+            Ok::<tls::PassthroughRoute, Error>(tls::PassthroughRoute::new(&x.hostname, build_handler(&x.handler, build).await?))
+        })).await?, build_optional_handler(s.handler.as_deref(), build).await?, &s.limits)),
+        Handler::StartTls(s) => match s.handler.as_ref() {
+            Handler::Tls(_) | Handler::LazyTls(_) => Box::new(StartTlsHandler::new(s.protocol, s.hostname.as_deref(), build_handler(&s.handler, build).await?, s.timeout)),
+            _ => return Err("The handler of starttls must be a tls or lazytls handler".into())
+        },
+        Handler::Detect(s) => {
+            let mut routes: Vec<(Protocol, handler::SendableHandler)> = Vec::new();
+            for (protocol, handler) in [
+                (Protocol::Tls, &s.tls),
+                (Protocol::Http, &s.http),
+                (Protocol::Http2, &s.http2),
+                (Protocol::Ssh, &s.ssh),
+                (Protocol::Proxy, &s.proxy_protocol)
+            ] {
+                if let Some(handler) = handler {
+                    routes.push((protocol, build_handler(handler, build).await?));
+                }
+            }
+            Box::new(DetectHandler::new(routes, build_optional_handler(s.fallback.as_deref(), build).await?, s.timeout))
+        },
+        Handler::Alpn(s) => Box::new(AlpnHandler::new(try_join_all(s.routes.iter().enumerate().map(|(i, x)| async move {
+            // A route without protocols could never be chosen
+            let route = tls::AlpnRoute::new(x.protocols.as_ref(), build_handler(&x.handler, build).await?);
+            match route.protocols().is_empty() {
+                true => Err::<tls::AlpnRoute, Error>(format!("ALPN route {} needs protocols, its handler doesn't advertise any", i + 1).into()),
+                false => Ok(route)
+            }
+        })).await?, build_optional_handler(s.fallback.as_deref(), build).await?)),
+        Handler::ProxyProtocol(s) => Box::new(ProxyProtocolHandler::new(&s.trusted, s.trust_unix.unwrap_or(false), build_handler(&s.handler, build).await?, s.timeout)?),
+        Handler::Http(s) => Box::new(HttpHandler::new(build_service(&s.service, s.layers.as_ref(), build).await?)),
+        Handler::Http1(s) => Box::new(Http1Handler::new(build_service(&s.service, s.layers.as_ref(), build).await?)),
+        Handler::Http2(s) => Box::new(Http2Handler::new(build_service(&s.service, s.layers.as_ref(), build).await?))
     };
     Ok(handler)
 }
 
+async fn build_optional_handler(handler: Option<&Handler>, build: Build<'_>) -> Result<Option<handler::SendableHandler>, Error> {
+    Ok(match handler {
+        Some(handler) => Some(build_handler(handler, build).await?),
+        None => None
+    })
+}
+
 #[async_recursion]
-pub async fn build_service(service: &Service, layers: Option<&Vec<Layer>>) -> Result<Arc<dyn http::HttpService + Send + Sync>, Error> {
+pub async fn build_service<'a>(service: &'a Service, layers: Option<&'a Vec<Layer>>, build: Build<'a>) -> Result<Arc<dyn http::HttpService + Send + Sync>, Error> {
     let mut service: Arc<dyn http::HttpService + Send + Sync> = match service {
         Service::Hello => Arc::new(HelloService {}),
-        Service::Proxy(s) => Arc::new(ProxyService::new((&s.uri).try_into()?)),
+        Service::Metrics => Arc::new(MetricsService {}),
+        Service::Proxy(s) => Arc::new(ProxyService::new((&s.uri).try_into()?, s.proxy_protocol)),
         Service::File(s) => Arc::new(FileService::new(&s.path)),
-        Service::Router(s) => Arc::new(RouterService::new(join_all(s.routes.iter().map(|x| async {
-            http::Route {
+        Service::Router(s) => Arc::new(RouterService::new(try_join_all(s.routes.iter().map(|x| async {
+            Ok::<http::Route, Error>(http::Route {
                 route: x.path.clone(),
-                service: build_service(&x.service, None).await.unwrap()
-            }
-        })).await))
+                fingerprints: x.fingerprints.clone(),
+                service: build_service(&x.service, None, build).await?
+            })
+        })).await?))
     };
 
     if let Some(layers) = layers {
         for layer in layers {
             match layer {
-                Layer::Log(s) => service = Arc::new(LogLayer::new(service, &s.path).await?),
+                Layer::Log(s) if matches!(build, Build::Check(_)) => LogLayer::check(&s.path).await?,
+                Layer::Log(s) => service = Arc::new(LogLayer::new(service, &s.path, s.fingerprints.unwrap_or(false)).await?),
+                Layer::Authenticator(s) if matches!(build, Build::Check(_)) => AuthenticatorService::check(&s.discovery_url)?,
                 Layer::Authenticator(s) => service = Arc::new(AuthenticatorService::new(
                     service,
                     &s.discovery_url,
                     &s.client_id,
                     &s.client_secret
-                ).await?)
+                ).await?),
+                Layer::ClientCertificate(s) => service = Arc::new(ClientCertificateLayer::new(service, s)),
+                Layer::TlsHeaders(s) => service = Arc::new(TlsHeadersLayer::new(service, s)?),
+                Layer::Fingerprints(s) => service = Arc::new(FingerprintLayer::new(service, s))
             }
         }
     }
@@ -0,0 +1,39 @@
+use tokio::time::timeout;
+
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+use std::future::Future;
+use std::time::Duration;
+
+/// Coordinates stopping the listeners and draining the connections they accepted
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    tracker: TaskTracker
+}
+
+impl Shutdown {
+    /// Resolves when a shutdown starts, handlers use it to finish their connection gracefully
+    pub async fn cancelled(&self) {
+        self.token.cancelled().await
+    }
+
+    /// Spawn a connection task, or a task serving an upgraded connection, which has to finish before the drain is complete
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.tracker.spawn(task);
+    }
+
+    pub fn trigger(&self) {
+        self.token.cancel();
+        self.tracker.close();
+    }
+
+    /// Wait for all connections to finish, returns false when the timeout expired first
+    pub async fn drain(&self, duration: Duration) -> bool {
+        timeout(duration, self.tracker.wait()).await.is_ok()
+    }
+}
@@ -0,0 +1,33 @@
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+use std::io;
+
+pub enum Event {
+    Terminate,
+    Reload
+}
+
+/// Process signals, registered once so none are missed while handling a previous one
+pub struct Signals {
+    terminate: Signal,
+    interrupt: Signal,
+    hangup: Signal
+}
+
+impl Signals {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            terminate: signal(SignalKind::terminate())?,
+            interrupt: signal(SignalKind::interrupt())?,
+            hangup: signal(SignalKind::hangup())?
+        })
+    }
+
+    pub async fn recv(&mut self) -> Event {
+        tokio::select! {
+            _ = self.terminate.recv() => Event::Terminate,
+            _ = self.interrupt.recv() => Event::Terminate,
+            _ = self.hangup.recv() => Event::Reload
+        }
+    }
+}
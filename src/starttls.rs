@@ -0,0 +1,360 @@
+use async_trait::async_trait;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+use std::error::Error;
+use std::io::{self, ErrorKind};
+use std::time::Duration;
+
+use crate::handler::{Context, Handler, SendableHandler};
+use crate::io::ProxyStream;
+use crate::settings::StartTlsProtocol;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_HOSTNAME: &str = "localhost";
+const MAX_LINE: usize = 1024;
+const IMAP_CAPABILITIES: &str = "IMAP4rev1 STARTTLS LOGINDISABLED";
+const POSTGRES_SSL_REQUEST: u32 = 80877103;
+const POSTGRES_GSSENC_REQUEST: u32 = 80877104;
+
+/// Answers the plaintext exchange of a protocol up to its in-band TLS upgrade, then hands the connection to a TLS handler
+pub struct StartTlsHandler {
+    protocol: StartTlsProtocol,
+    hostname: String,
+    handler: SendableHandler,
+    timeout: Duration
+}
+
+impl StartTlsHandler {
+    pub fn new(protocol: StartTlsProtocol, hostname: Option<&str>, handler: SendableHandler, timeout: Option<u64>) -> Self {
+        Self {
+            protocol,
+            hostname: hostname.unwrap_or(DEFAULT_HOSTNAME).to_string(),
+            handler,
+            timeout: timeout.map(Duration::from_millis).unwrap_or(DEFAULT_TIMEOUT)
+        }
+    }
+
+    async fn smtp(&self, stream: &mut ProxyStream) -> io::Result<bool> {
+        let mut lines = Lines::new(stream);
+        lines.write(&format!("220 {} ESMTP\r\n", self.hostname)).await?;
+
+        while let Some(line) = lines.next().await? {
+            let response = match verb(&line).as_str() {
+                "EHLO" => format!("250-{}\r\n250 STARTTLS\r\n", self.hostname),
+                "HELO" => format!("250 {}\r\n", self.hostname),
+                "NOOP" | "RSET" => "250 2.0.0 OK\r\n".to_string(),
+                "STARTTLS" => return lines.upgrade("220 2.0.0 Ready to start TLS\r\n").await,
+                "QUIT" => return lines.close("221 2.0.0 Bye\r\n").await,
+                _ => "530 5.7.0 Must issue a STARTTLS command first\r\n".to_string()
+            };
+            lines.write(&response).await?;
+        }
+        Ok(false)
+    }
+
+    async fn imap(&self, stream: &mut ProxyStream) -> io::Result<bool> {
+        let mut lines = Lines::new(stream);
+        lines.write(&format!("* OK [CAPABILITY {}] {} ready\r\n", IMAP_CAPABILITIES, self.hostname)).await?;
+
+        while let Some(line) = lines.next().await? {
+            let (tag, command) = line.split_once(' ').unwrap_or((&line, ""));
+            let tag = if tag.is_empty() { "*" } else { tag };
+            let response = match verb(command).as_str() {
+                "CAPABILITY" => format!("* CAPABILITY {}\r\n{} OK CAPABILITY completed\r\n", IMAP_CAPABILITIES, tag),
+                "NOOP" => format!("{} OK NOOP completed\r\n", tag),
+                "STARTTLS" => return lines.upgrade(&format!("{} OK Begin TLS negotiation now\r\n", tag)).await,
+                "LOGOUT" => return lines.close(&format!("* BYE {} logging out\r\n{} OK LOGOUT completed\r\n", self.hostname, tag)).await,
+                _ => format!("{} BAD Command not allowed before STARTTLS\r\n", tag)
+            };
+            lines.write(&response).await?;
+        }
+        Ok(false)
+    }
+
+    async fn pop3(&self, stream: &mut ProxyStream) -> io::Result<bool> {
+        let mut lines = Lines::new(stream);
+        lines.write(&format!("+OK {} ready\r\n", self.hostname)).await?;
+
+        while let Some(line) = lines.next().await? {
+            let response = match verb(&line).as_str() {
+                "CAPA" => "+OK Capability list follows\r\nSTLS\r\n.\r\n",
+                "STLS" => return lines.upgrade("+OK Begin TLS negotiation\r\n").await,
+                "QUIT" => return lines.close("+OK Bye\r\n").await,
+                _ => "-ERR Command not allowed before STLS\r\n"
+            };
+            lines.write(response).await?;
+        }
+        Ok(false)
+    }
+
+    async fn postgres(&self, stream: &mut ProxyStream) -> io::Result<bool> {
+        loop {
+            let mut header = [0; 8];
+            stream.read_exact(&mut header).await?;
+            let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+            let code = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+
+            match (length, code) {
+                (8, POSTGRES_SSL_REQUEST) => {
+                    stream.write_all(b"S").await?;
+                    return Ok(true);
+                },
+                // Clients fall back to an SSLRequest when GSSAPI encryption is declined
+                (8, POSTGRES_GSSENC_REQUEST) => stream.write_all(b"N").await?,
+                _ => {
+                    stream.write_all(&postgres_error("SSL connection is required")).await?;
+                    return Ok(false);
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for StartTlsHandler {
+    async fn handle(&self, mut stream: ProxyStream, mut ctx: Context) -> Result<(), Box<dyn Error>> {
+        let upgrade = timeout(self.timeout, async {
+            match self.protocol {
+                StartTlsProtocol::Smtp => self.smtp(&mut stream).await,
+                StartTlsProtocol::Imap => self.imap(&mut stream).await,
+                StartTlsProtocol::Pop3 => self.pop3(&mut stream).await,
+                StartTlsProtocol::Postgres => self.postgres(&mut stream).await
+            }
+        }).await.map_err(|_| io::Error::new(ErrorKind::TimedOut, "STARTTLS negotiation timed out"))??;
+
+        if !upgrade {
+            return Ok(());
+        }
+
+        ctx.starttls = Some(self.protocol);
+        self.handler.handle(stream, ctx).await
+    }
+}
+
+/// Read the greeting of a plaintext backend, the client already got ours before upgrading.
+/// Anything the backend sent after the greeting is returned
+pub async fn skip_greeting<S: AsyncRead + Unpin>(stream: &mut S, protocol: StartTlsProtocol) -> io::Result<Vec<u8>> {
+    let mut lines = Lines::new(stream);
+    let accepted = match protocol {
+        StartTlsProtocol::Smtp => loop {
+            // Continuation lines have a hyphen after the reply code
+            match lines.next().await? {
+                Some(line) if line.as_bytes().get(3) == Some(&b'-') => continue,
+                line => break line.is_some_and(|x| x.starts_with("220"))
+            }
+        },
+        StartTlsProtocol::Imap => lines.next().await?.is_some_and(|x| x.starts_with("* OK")),
+        StartTlsProtocol::Pop3 => lines.next().await?.is_some_and(|x| x.starts_with("+OK")),
+        StartTlsProtocol::Postgres => true
+    };
+
+    match accepted {
+        true => Ok(lines.buf),
+        false => Err(io::Error::other("backend refused the connection"))
+    }
+}
+
+/// The command of a line in upper case
+fn verb(line: &str) -> String {
+    line.split_whitespace().next().unwrap_or_default().to_ascii_uppercase()
+}
+
+/// A FATAL ErrorResponse message
+fn postgres_error(message: &str) -> Vec<u8> {
+    let fields = [b"SFATAL\0".as_slice(), b"VFATAL\0", b"C28000\0", b"M", message.as_bytes(), b"\0\0"].concat();
+    [b"E".as_slice(), &(fields.len() as u32 + 4).to_be_bytes(), &fields].concat()
+}
+
+/// Reads CRLF terminated lines, keeping what was read past the current one
+struct Lines<'a, S> {
+    stream: &'a mut S,
+    buf: Vec<u8>
+}
+
+impl<'a, S: AsyncRead + Unpin> Lines<'a, S> {
+    fn new(stream: &'a mut S) -> Self {
+        Self {
+            stream,
+            buf: Vec::new()
+        }
+    }
+
+    async fn next(&mut self) -> io::Result<Option<String>> {
+        loop {
+            if let Some(end) = self.buf.iter().position(|&x| x == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=end).collect();
+                return Ok(Some(String::from_utf8_lossy(&line).trim_end().to_string()));
+            }
+            if self.buf.len() > MAX_LINE {
+                return Err(io::Error::new(ErrorKind::InvalidData, "line too long"));
+            }
+            if self.stream.read_buf(&mut self.buf).await? == 0 {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Lines<'_, S> {
+    async fn write(&mut self, response: &str) -> io::Result<()> {
+        self.stream.write_all(response.as_bytes()).await
+    }
+
+    /// Commands pipelined behind the upgrade would be injected into the encrypted session (CVE-2011-0411)
+    async fn upgrade(&mut self, response: &str) -> io::Result<bool> {
+        if !self.buf.is_empty() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "data sent before the TLS handshake"));
+        }
+        self.write(response).await?;
+        Ok(true)
+    }
+
+    async fn close(&mut self, response: &str) -> io::Result<bool> {
+        self.write(response).await?;
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+
+    /// Stands in for the TLS handler, confirms the connection was handed over after the upgrade
+    struct Upgraded;
+
+    #[async_trait]
+    impl Handler for Upgraded {
+        async fn handle(&self, mut stream: ProxyStream, ctx: Context) -> Result<(), Box<dyn Error>> {
+            if ctx.starttls.is_some() {
+                stream.write_all(b"TLS").await?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Play the client side, each step sends bytes and expects the exact reply, then everything up to the close is returned
+    async fn client(mut stream: DuplexStream, steps: &[(&[u8], &[u8])]) -> Vec<u8> {
+        for (send, expect) in steps {
+            stream.write_all(send).await.unwrap();
+            let mut reply = vec![0; expect.len()];
+            stream.read_exact(&mut reply).await.unwrap();
+            assert_eq!(String::from_utf8_lossy(&reply), String::from_utf8_lossy(expect));
+        }
+
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        rest
+    }
+
+    async fn exchange(protocol: StartTlsProtocol, steps: &[(&[u8], &[u8])]) -> (bool, Vec<u8>) {
+        let (client_stream, server_stream) = duplex(1024);
+        let handler = StartTlsHandler::new(protocol, Some("mail.example.com"), Box::new(Upgraded), None);
+        let (result, rest) = tokio::join!(
+            handler.handle(ProxyStream::new_dynamic(Box::pin(server_stream)), Context::default()),
+            client(client_stream, steps)
+        );
+        (result.is_ok(), rest)
+    }
+
+    fn postgres_request(code: u32) -> Vec<u8> {
+        [8u32.to_be_bytes(), code.to_be_bytes()].concat()
+    }
+
+    #[tokio::test]
+    async fn smtp() {
+        let (ok, rest) = exchange(StartTlsProtocol::Smtp, &[
+            (b"", b"220 mail.example.com ESMTP\r\n"),
+            (b"EHLO client.example.com\r\n", b"250-mail.example.com\r\n250 STARTTLS\r\n"),
+            (b"MAIL FROM:<a@example.com>\r\n", b"530 5.7.0 Must issue a STARTTLS command first\r\n"),
+            (b"starttls\r\n", b"220 2.0.0 Ready to start TLS\r\n")
+        ]).await;
+        assert!(ok);
+        assert_eq!(rest, b"TLS");
+
+        let (ok, rest) = exchange(StartTlsProtocol::Smtp, &[
+            (b"", b"220 mail.example.com ESMTP\r\n"),
+            (b"QUIT\r\n", b"221 2.0.0 Bye\r\n")
+        ]).await;
+        assert!(ok);
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn imap() {
+        let (ok, rest) = exchange(StartTlsProtocol::Imap, &[
+            (b"", b"* OK [CAPABILITY IMAP4rev1 STARTTLS LOGINDISABLED] mail.example.com ready\r\n"),
+            (b"a1 LOGIN user secret\r\n", b"a1 BAD Command not allowed before STARTTLS\r\n"),
+            (b"a2 CAPABILITY\r\n", b"* CAPABILITY IMAP4rev1 STARTTLS LOGINDISABLED\r\na2 OK CAPABILITY completed\r\n"),
+            (b"a3 STARTTLS\r\n", b"a3 OK Begin TLS negotiation now\r\n")
+        ]).await;
+        assert!(ok);
+        assert_eq!(rest, b"TLS");
+    }
+
+    #[tokio::test]
+    async fn pop3() {
+        let (ok, rest) = exchange(StartTlsProtocol::Pop3, &[
+            (b"", b"+OK mail.example.com ready\r\n"),
+            (b"CAPA\r\n", b"+OK Capability list follows\r\nSTLS\r\n.\r\n"),
+            (b"USER user\r\n", b"-ERR Command not allowed before STLS\r\n"),
+            (b"STLS\r\n", b"+OK Begin TLS negotiation\r\n")
+        ]).await;
+        assert!(ok);
+        assert_eq!(rest, b"TLS");
+    }
+
+    #[tokio::test]
+    async fn postgres() {
+        let (ok, rest) = exchange(StartTlsProtocol::Postgres, &[
+            (&postgres_request(POSTGRES_GSSENC_REQUEST), b"N"),
+            (&postgres_request(POSTGRES_SSL_REQUEST), b"S")
+        ]).await;
+        assert!(ok);
+        assert_eq!(rest, b"TLS");
+
+        // A plaintext StartupMessage is refused
+        let startup = [&12u32.to_be_bytes()[..], &196608u32.to_be_bytes(), b"user"].concat();
+        let (ok, rest) = exchange(StartTlsProtocol::Postgres, &[(&startup, b"")]).await;
+        assert!(ok);
+        assert_eq!(rest, postgres_error("SSL connection is required"));
+    }
+
+    #[tokio::test]
+    async fn pipelined_after_starttls() {
+        let (ok, rest) = exchange(StartTlsProtocol::Smtp, &[
+            (b"", b"220 mail.example.com ESMTP\r\n"),
+            (b"STARTTLS\r\nMAIL FROM:<a@example.com>\r\n", b"")
+        ]).await;
+        assert!(!ok);
+        assert!(rest.is_empty());
+
+        let (ok, rest) = exchange(StartTlsProtocol::Pop3, &[
+            (b"", b"+OK mail.example.com ready\r\n"),
+            (b"STLS\r\nUSER user\r\n", b"")
+        ]).await;
+        assert!(!ok);
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn backend_greeting() {
+        let mut smtp = &b"220-mail.example.com ESMTP\r\n220 ready\r\n250 early"[..];
+        assert_eq!(skip_greeting(&mut smtp, StartTlsProtocol::Smtp).await.unwrap(), b"250 early");
+
+        let mut imap = &b"* OK ready\r\n"[..];
+        assert!(skip_greeting(&mut imap, StartTlsProtocol::Imap).await.unwrap().is_empty());
+
+        let mut pop3 = &b"-ERR busy\r\n"[..];
+        assert!(skip_greeting(&mut pop3, StartTlsProtocol::Pop3).await.is_err());
+
+        let mut refused = &b"554 no service\r\n"[..];
+        assert!(skip_greeting(&mut refused, StartTlsProtocol::Smtp).await.is_err());
+
+        let mut closed = &b""[..];
+        assert!(skip_greeting(&mut closed, StartTlsProtocol::Imap).await.is_err());
+    }
+}
@@ -0,0 +1,471 @@
+use instant_acme::{Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, NewAccount, NewOrder, OrderStatus, RetryPolicy};
+
+use hyper::Uri;
+
+use rcgen::{CertificateParams, CustomExtension, KeyPair};
+
+use sha2::{Digest, Sha256};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+use tokio_rustls::rustls::pki_types::PrivateKeyDer;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::StartHandshake;
+
+use x509_parser::parse_x509_certificate;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex, RwLock, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::Error;
+use crate::settings::{self, AcmeChallenge, Build};
+
+use super::{load_certified_key, provider, CertificateError, CertificateErrorKind};
+
+const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+const DEFAULT_RENEW_BEFORE: u64 = 30;
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Pending challenges are shared by all handlers, the validation request may arrive on any listener
+static HTTP_CHALLENGES: LazyLock<RwLock<HashMap<String, String>>> = LazyLock::new(Default::default);
+static TLS_CHALLENGES: LazyLock<RwLock<HashMap<String, Arc<CertifiedKey>>>> = LazyLock::new(Default::default);
+
+/// Certificate source which obtains and renews its certificate from an ACME server
+#[derive(Debug)]
+pub struct AcmeResolver {
+    renewal: Arc<Renewal>
+}
+
+/// Renewals of the running server, one per distinct settings so reloads take over the running one instead of ordering again
+#[derive(Default)]
+pub struct AcmeRenewals {
+    // Used by the running handlers
+    running: Mutex<Vec<Weak<Renewal>>>,
+    // Used by the handlers being built, they replace the running ones once the whole configuration is loaded
+    pending: Mutex<Vec<Weak<Renewal>>>
+}
+
+/// Current certificate of a set of domains and the task keeping it renewed
+#[derive(Debug)]
+struct Renewal {
+    settings: settings::Acme,
+    current: Arc<RwLock<Option<Arc<CertifiedKey>>>>,
+    task: Option<JoinHandle<()>>
+}
+
+/// Registers challenge responses for the duration of an order
+#[derive(Default)]
+struct Challenges {
+    tokens: Vec<String>,
+    domains: Vec<String>
+}
+
+impl AcmeResolver {
+    pub fn new(settings: &settings::Acme, build: Build<'_>) -> Result<Self, CertificateError> {
+        settings.directory.as_deref().unwrap_or(LETS_ENCRYPT).parse::<Uri>()
+            .map_err(|e| CertificateError::new(&settings.state_dir, CertificateErrorKind::Invalid(format!("invalid ACME directory: {}", e))))?;
+        if settings.domains.is_empty() {
+            return Err(CertificateError::new(&settings.state_dir, CertificateErrorKind::Invalid("no acme domains configured".to_string())));
+        }
+
+        let renewal = match build {
+            Build::Run(renewals, _) => renewals.get(settings)?,
+            Build::Check(_) => Arc::new(Renewal::new(settings, false)?)
+        };
+        Ok(Self { renewal })
+    }
+}
+
+impl ResolvesServerCert for AcmeResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.renewal.current.read().unwrap().clone()
+    }
+}
+
+impl AcmeRenewals {
+    /// Renewal with the same settings, either running already or started for the configuration being built
+    fn get(&self, settings: &settings::Acme) -> Result<Arc<Renewal>, CertificateError> {
+        let running = self.running.lock().unwrap();
+        let mut pending = self.pending.lock().unwrap();
+
+        let existing = running.iter().chain(pending.iter())
+            .filter_map(Weak::upgrade)
+            .find(|x| x.settings == *settings);
+        let renewal = match existing {
+            Some(renewal) => renewal,
+            None => Arc::new(Renewal::new(settings, true)?)
+        };
+
+        if !pending.iter().any(|x| x.ptr_eq(&Arc::downgrade(&renewal))) {
+            pending.push(Arc::downgrade(&renewal));
+        }
+        Ok(renewal)
+    }
+
+    /// The configuration being built is loaded, renewals it doesn't use are stopped.
+    /// Resolvers of the previous configuration keep serving their last certificate until they are dropped
+    pub fn commit(&self) {
+        let mut running = self.running.lock().unwrap();
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+
+        for renewal in running.iter().filter_map(Weak::upgrade) {
+            if !pending.iter().any(|x| x.ptr_eq(&Arc::downgrade(&renewal))) {
+                renewal.stop();
+            }
+        }
+        *running = pending.into_iter().filter(|x| x.strong_count() > 0).collect();
+    }
+
+    /// The configuration being built failed to load, renewals it started stop when its handlers are dropped
+    pub fn discard(&self) {
+        self.pending.lock().unwrap().clear();
+    }
+}
+
+impl Renewal {
+    fn new(settings: &settings::Acme, run: bool) -> Result<Self, CertificateError> {
+        if run {
+            fs::create_dir_all(&settings.state_dir)
+                .map_err(|e| CertificateError::new(&settings.state_dir, CertificateErrorKind::Read(e)))?;
+        }
+
+        // A previously issued certificate is served right away, renewal happens in the background
+        let (certificate, key) = certificate_paths(&settings.state_dir, &settings.domains);
+        let current = match load_certified_key(&certificate, &key) {
+            Ok(key) => Some(Arc::new(key)),
+            Err(CertificateError { kind: CertificateErrorKind::Read(_), .. }) => None,
+            Err(e) => {
+                println!("Ignoring stored certificate: {}", e);
+                None
+            }
+        };
+
+        let current = Arc::new(RwLock::new(current));
+        let task = run.then(|| tokio::spawn(renew(settings.clone(), current.clone())));
+
+        Ok(Self {
+            settings: settings.clone(),
+            current,
+            task
+        })
+    }
+
+    fn stop(&self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for Renewal {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl Challenges {
+    fn add_http(&mut self, token: &str, key_authorization: &str) {
+        HTTP_CHALLENGES.write().unwrap().insert(token.to_string(), key_authorization.to_string());
+        self.tokens.push(token.to_string());
+    }
+
+    fn add_tls(&mut self, domain: &str, key: CertifiedKey) {
+        TLS_CHALLENGES.write().unwrap().insert(domain.to_string(), Arc::new(key));
+        self.domains.push(domain.to_string());
+    }
+}
+
+impl Drop for Challenges {
+    fn drop(&mut self) {
+        let mut tokens = HTTP_CHALLENGES.write().unwrap();
+        self.tokens.iter().for_each(|x| { tokens.remove(x); });
+
+        let mut domains = TLS_CHALLENGES.write().unwrap();
+        self.domains.iter().for_each(|x| { domains.remove(x); });
+    }
+}
+
+/// Key authorization for a pending HTTP-01 challenge token
+pub fn http_challenge(token: &str) -> Option<String> {
+    HTTP_CHALLENGES.read().unwrap().get(token).cloned()
+}
+
+/// Complete the handshake of TLS-ALPN-01 validation connections, other connections are handed back
+pub async fn accept_challenge<IO>(acceptor: StartHandshake<IO>) -> io::Result<Option<StartHandshake<IO>>>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let hello = acceptor.client_hello();
+    let config = hello.server_name()
+        .filter(|_| hello.alpn().is_some_and(|mut x| x.any(|p| p == ACME_TLS_ALPN)))
+        .and_then(challenge_config);
+
+    match config {
+        Some(config) => {
+            acceptor.into_stream(config).await?;
+            Ok(None)
+        },
+        None => Ok(Some(acceptor))
+    }
+}
+
+/// Config for a handshake which only answers a pending TLS-ALPN-01 challenge
+fn challenge_config(server_name: &str) -> Option<Arc<ServerConfig>> {
+    let key = TLS_CHALLENGES.read().unwrap().get(server_name).cloned()?;
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(ChallengeResolver(key)));
+
+    config.alpn_protocols = vec![ACME_TLS_ALPN.to_vec()];
+    Some(Arc::new(config))
+}
+
+#[derive(Debug)]
+struct ChallengeResolver(Arc<CertifiedKey>);
+
+impl ResolvesServerCert for ChallengeResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+}
+
+async fn renew(settings: settings::Acme, current: Arc<RwLock<Option<Arc<CertifiedKey>>>>) {
+    let renew_before = settings.renew_before.unwrap_or(DEFAULT_RENEW_BEFORE) * 24 * 60 * 60;
+    loop {
+        let renew_at = current.read().unwrap().as_deref().and_then(|x| renewal_time(x, renew_before));
+        let wait = match renew_at.and_then(|x| x.duration_since(SystemTime::now()).ok()) {
+            Some(wait) => wait.min(CHECK_INTERVAL),
+            None => match issue(&settings).await {
+                Ok(key) => {
+                    *current.write().unwrap() = Some(Arc::new(key));
+                    continue;
+                },
+                Err(e) => {
+                    println!("Failed to obtain certificate for {}: {}", settings.domains.join(", "), e);
+                    RETRY_INTERVAL
+                }
+            }
+        };
+        sleep(wait).await;
+    }
+}
+
+/// Renew ahead of expiry, but never in the first two thirds of the lifetime of short lived certificates
+fn renewal_time(key: &CertifiedKey, renew_before: u64) -> Option<SystemTime> {
+    let (_, leaf) = parse_x509_certificate(key.end_entity_cert().ok()?).ok()?;
+    let validity = leaf.validity();
+    let not_before = validity.not_before.timestamp();
+    let not_after = validity.not_after.timestamp();
+
+    let margin = (renew_before as i64).min((not_after - not_before) / 3);
+    let at = u64::try_from(not_after - margin).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(at))
+}
+
+async fn issue(settings: &settings::Acme) -> Result<CertifiedKey, Error> {
+    let account = account(settings).await?;
+    let identifiers = settings.domains.iter().map(|x| Identifier::Dns(x.clone())).collect::<Vec<_>>();
+    let mut order = account.new_order(&NewOrder::new(&identifiers)).await?;
+
+    let challenge_type = match settings.challenge.unwrap_or(AcmeChallenge::Http01) {
+        AcmeChallenge::Http01 => ChallengeType::Http01,
+        AcmeChallenge::TlsAlpn01 => ChallengeType::TlsAlpn01
+    };
+
+    let mut challenges = Challenges::default();
+    let mut authorizations = order.authorizations();
+    while let Some(authorization) = authorizations.next().await {
+        let mut authorization = authorization?;
+        if authorization.status != AuthorizationStatus::Pending {
+            continue;
+        }
+
+        let mut challenge = authorization.challenge(challenge_type.clone())
+            .ok_or(format!("server doesn't offer the {:?} challenge", challenge_type))?;
+        let key_authorization = challenge.key_authorization();
+        match challenge_type {
+            ChallengeType::TlsAlpn01 => {
+                let Identifier::Dns(domain) = challenge.identifier().identifier else {
+                    return Err("tls-alpn-01 is only supported for dns identifiers".into());
+                };
+                let key = challenge_certificate(domain, key_authorization.digest().as_ref())?;
+                challenges.add_tls(domain, key);
+            },
+            _ => challenges.add_http(&challenge.token, key_authorization.as_str())
+        }
+        challenge.set_ready().await?;
+    }
+
+    let status = order.poll_ready(&RetryPolicy::default()).await?;
+    if status != OrderStatus::Ready {
+        return Err(format!("order ended with status {:?}", status).into());
+    }
+
+    let key = order.finalize().await?;
+    let certificate = order.poll_certificate(&RetryPolicy::default()).await?;
+    drop(challenges);
+
+    let (certificate_path, key_path) = certificate_paths(&settings.state_dir, &settings.domains);
+    fs::create_dir_all(certificate_path.parent().unwrap())?;
+    write_private(&key_path, &key)?;
+    fs::write(&certificate_path, &certificate)?;
+
+    println!("Obtained certificate for {}", settings.domains.join(", "));
+    Ok(load_certified_key(&certificate_path, &key_path)?)
+}
+
+/// Load the account registered with the directory, or create and store a new one
+async fn account(settings: &settings::Acme) -> Result<Account, Error> {
+    let directory = settings.directory.as_deref().unwrap_or(LETS_ENCRYPT);
+    let builder = match &settings.ca {
+        Some(ca) => Account::builder_with_root(ca)?,
+        None => Account::builder()?
+    };
+
+    // Accounts are bound to a server, so switching between staging and production gets a new one
+    let host = directory.parse::<Uri>()?.host().unwrap_or_default().to_string();
+    let path = settings.state_dir.join(format!("account-{}.json", host));
+    if let Ok(credentials) = fs::read_to_string(&path) {
+        let credentials: AccountCredentials = serde_json::from_str(&credentials)?;
+        return Ok(builder.from_credentials(credentials).await?);
+    }
+
+    let contact = settings.contact.iter().flatten().map(String::as_str).collect::<Vec<_>>();
+    let (account, credentials) = builder.create(&NewAccount {
+        contact: &contact,
+        terms_of_service_agreed: settings.terms_of_service_agreed.unwrap_or(false),
+        only_return_existing: false
+    }, directory.to_string(), None).await?;
+
+    write_private(&path, &serde_json::to_string_pretty(&credentials)?)?;
+    Ok(account)
+}
+
+/// Self signed certificate carrying the acmeIdentifier extension required by RFC 8737
+fn challenge_certificate(domain: &str, digest: &[u8]) -> Result<CertifiedKey, Error> {
+    let mut params = CertificateParams::new(vec![domain.to_string()])?;
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest)];
+
+    let key = KeyPair::generate()?;
+    let certificate = params.self_signed(&key)?;
+    let key = PrivateKeyDer::try_from(key.serialize_der())?;
+
+    Ok(CertifiedKey::from_der(vec![certificate.der().clone()], key, &provider())?)
+}
+
+/// Each set of domains has its own certificate, stored in a directory named after the first domain
+fn certificate_paths(state_dir: &Path, domains: &[String]) -> (PathBuf, PathBuf) {
+    let dir = match domains {
+        [domain] => state_dir.join(domain),
+        _ => {
+            let hash = Sha256::digest(domains.join(",").as_bytes()).iter().take(4).map(|x| format!("{:02x}", x)).collect::<String>();
+            state_dir.join(format!("{}+{}", domains[0], hash))
+        }
+    };
+    (dir.join("fullchain.pem"), dir.join("privkey.pem"))
+}
+
+/// Write a file holding key material, readable by the owner only
+fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acme(domains: &[&str]) -> settings::Acme {
+        settings::Acme {
+            // Nothing listens here, so orders fail and are retried much later
+            directory: Some("http://127.0.0.1:1/directory".to_string()),
+            contact: None,
+            domains: domains.iter().map(|x| x.to_string()).collect(),
+            state_dir: std::env::temp_dir().join(format!("rproxy-acme-{}", std::process::id())),
+            challenge: None,
+            ca: None,
+            renew_before: None,
+            terms_of_service_agreed: None
+        }
+    }
+
+    async fn running(renewal: &Renewal) -> bool {
+        sleep(Duration::from_millis(10)).await;
+        renewal.task.as_ref().is_some_and(|x| !x.is_finished())
+    }
+
+    #[tokio::test]
+    async fn reused_across_loads() {
+        let renewals = AcmeRenewals::default();
+        let first = renewals.get(&acme(&["a.test"])).unwrap();
+        assert!(Arc::ptr_eq(&first, &renewals.get(&acme(&["a.test"])).unwrap()));
+        renewals.commit();
+
+        let second = renewals.get(&acme(&["a.test"])).unwrap();
+        renewals.commit();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(running(&first).await);
+    }
+
+    #[tokio::test]
+    async fn replaced_on_commit() {
+        let renewals = AcmeRenewals::default();
+        let old = renewals.get(&acme(&["a.test"])).unwrap();
+        renewals.commit();
+
+        // Sharing the first domain doesn't make it the same certificate
+        let new = renewals.get(&acme(&["a.test", "b.test"])).unwrap();
+        assert!(!Arc::ptr_eq(&old, &new));
+        assert!(running(&old).await);
+
+        renewals.commit();
+        assert!(!running(&old).await);
+        assert!(running(&new).await);
+    }
+
+    #[tokio::test]
+    async fn kept_on_failed_load() {
+        let renewals = AcmeRenewals::default();
+        let old = renewals.get(&acme(&["a.test"])).unwrap();
+        renewals.commit();
+
+        // The failed configuration used the running renewal and started another one
+        let reused = renewals.get(&acme(&["a.test"])).unwrap();
+        let new = renewals.get(&acme(&["c.test"])).unwrap();
+        renewals.discard();
+        drop((reused, new));
+        assert!(running(&old).await);
+
+        // The next successful load only knows about the renewals it used
+        renewals.get(&acme(&["a.test"])).unwrap();
+        renewals.commit();
+        assert!(running(&old).await);
+    }
+
+    #[test]
+    fn paths_by_domain_set() {
+        let dir = Path::new("/state");
+        assert_eq!(certificate_paths(dir, &["a.test".to_string()]).0, Path::new("/state/a.test/fullchain.pem"));
+
+        let (certificate, _) = certificate_paths(dir, &["a.test".to_string(), "b.test".to_string()]);
+        assert!(certificate.starts_with("/state") && certificate.parent().unwrap().to_str().unwrap().starts_with("/state/a.test+"));
+        assert_ne!(certificate, certificate_paths(dir, &["a.test".to_string(), "c.test".to_string()]).0);
+    }
+}
@@ -0,0 +1,134 @@
+use async_trait::async_trait;
+
+use std::error::Error;
+
+use crate::handler::{Context, Handler, SendableHandler};
+use crate::io::ProxyStream;
+
+pub struct AlpnRoute {
+    protocols: Vec<String>,
+    handler: SendableHandler
+}
+
+/// Dispatches terminated TLS connections by the negotiated ALPN protocol
+pub struct AlpnHandler {
+    routes: Vec<AlpnRoute>,
+    fallback: Option<SendableHandler>
+}
+
+impl AlpnRoute {
+    /// Without explicit protocols the route takes the protocols advertised by its handler
+    pub fn new(protocols: Option<&Vec<String>>, handler: SendableHandler) -> Self {
+        Self {
+            protocols: protocols.cloned().or_else(|| handler.alpn_protocols()).unwrap_or_default(),
+            handler
+        }
+    }
+
+    pub fn protocols(&self) -> &[String] {
+        &self.protocols
+    }
+}
+
+impl AlpnHandler {
+    pub fn new(routes: Vec<AlpnRoute>, fallback: Option<SendableHandler>) -> Self {
+        Self {
+            routes,
+            fallback
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for AlpnHandler {
+    async fn handle(&self, stream: ProxyStream, ctx: Context) -> Result<(), Box<dyn Error>> {
+        let handler = self.routes.iter()
+            .find(|x| ctx.alpn.as_ref().is_some_and(|alpn| x.protocols.contains(alpn)))
+            .map(|x| &x.handler)
+            .or(self.fallback.as_ref())
+            .ok_or_else(|| format!("No handler for ALPN protocol {}", ctx.alpn.as_deref().unwrap_or("-")))?;
+
+        handler.handle(stream, ctx).await
+    }
+
+    /// Union of the route protocols in order of preference
+    fn alpn_protocols(&self) -> Option<Vec<String>> {
+        let mut protocols: Vec<String> = Vec::new();
+        let fallback = self.fallback.as_ref().and_then(|x| x.alpn_protocols()).unwrap_or_default();
+        for protocol in self.routes.iter().flat_map(|x| x.protocols.iter()).chain(fallback.iter()) {
+            if !protocols.contains(protocol) {
+                protocols.push(protocol.clone());
+            }
+        }
+
+        Some(protocols).filter(|x| !x.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// Answers with its name, so the test can tell which route took the connection
+    struct Named(&'static str, Option<&'static [&'static str]>);
+
+    #[async_trait]
+    impl Handler for Named {
+        async fn handle(&self, mut stream: ProxyStream, _ctx: Context) -> Result<(), Box<dyn Error>> {
+            stream.write_all(self.0.as_bytes()).await?;
+            Ok(())
+        }
+
+        fn alpn_protocols(&self) -> Option<Vec<String>> {
+            self.1.map(|x| x.iter().map(|x| x.to_string()).collect())
+        }
+    }
+
+    fn route(protocols: Option<&[&str]>, handler: Named) -> AlpnRoute {
+        AlpnRoute::new(protocols.map(|x| x.iter().map(|x| x.to_string()).collect()).as_ref(), Box::new(handler))
+    }
+
+    async fn dispatch(handler: &AlpnHandler, alpn: Option<&str>) -> Option<String> {
+        let (mut client, server) = duplex(64);
+        let ctx = Context {
+            alpn: alpn.map(str::to_string),
+            ..Default::default()
+        };
+        handler.handle(ProxyStream::new_dynamic(Box::pin(server)), ctx).await.ok()?;
+
+        let mut name = String::new();
+        client.read_to_string(&mut name).await.unwrap();
+        Some(name)
+    }
+
+    #[tokio::test]
+    async fn dispatch_by_protocol() {
+        let handler = AlpnHandler::new(vec![
+            route(Some(&["acme-tls/1"]), Named("acme", None)),
+            route(None, Named("http", Some(&["h2", "http/1.1"])))
+        ], Some(Box::new(Named("fallback", None))));
+
+        assert_eq!(dispatch(&handler, Some("h2")).await.as_deref(), Some("http"));
+        assert_eq!(dispatch(&handler, Some("http/1.1")).await.as_deref(), Some("http"));
+        assert_eq!(dispatch(&handler, Some("acme-tls/1")).await.as_deref(), Some("acme"));
+        assert_eq!(dispatch(&handler, Some("imap")).await.as_deref(), Some("fallback"));
+        assert_eq!(dispatch(&handler, None).await.as_deref(), Some("fallback"));
+
+        let handler = AlpnHandler::new(vec![route(Some(&["h2"]), Named("h2", None))], None);
+        assert_eq!(dispatch(&handler, Some("http/1.1")).await, None);
+    }
+
+    #[test]
+    fn advertised_union() {
+        let handler = AlpnHandler::new(vec![
+            route(Some(&["h2", "smtp"]), Named("first", None)),
+            route(None, Named("second", Some(&["http/1.1", "h2"])))
+        ], Some(Box::new(Named("fallback", Some(&["imap", "smtp"])))));
+
+        // Routes in configured order then the fallback, each protocol once at its first position
+        assert_eq!(handler.alpn_protocols().unwrap(), ["h2", "smtp", "http/1.1", "imap"]);
+        assert_eq!(AlpnHandler::new(vec![], Some(Box::new(Named("fallback", None)))).alpn_protocols(), None);
+    }
+}
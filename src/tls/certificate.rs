@@ -0,0 +1,376 @@
+use rustls_pemfile::{certs, private_key};
+
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+use tokio_rustls::rustls::client::ResolvesClientCert;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::crypto::CryptoProvider;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{self, InconsistentKeys, RootCertStore, ServerConfig, SignatureAlgorithm, SignatureScheme};
+
+use x509_parser::parse_x509_certificate;
+use x509_parser::time::ASN1Time;
+
+use std::error;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::settings::Build;
+
+use super::ocsp::{OcspSource, OcspStapler};
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum CertificateErrorKind {
+    Read(io::Error),
+    NoCertificates,
+    NoKey,
+    KeyMismatch,
+    Expired(ASN1Time),
+    NotYetValid(ASN1Time),
+    Invalid(String),
+    Rustls(rustls::Error)
+}
+
+/// Failure to load the certificate or key of a TLS handler
+#[derive(Debug)]
+pub struct CertificateError {
+    pub path: PathBuf,
+    pub hostname: Option<String>,
+    pub kind: CertificateErrorKind
+}
+
+/// Certificate loaded from PEM files, reloaded when the files change on disk
+#[derive(Debug)]
+pub struct FileResolver {
+    current: Arc<RwLock<Arc<CertifiedKey>>>,
+    tasks: Vec<JoinHandle<()>>
+}
+
+/// Picks a certificate with a key usable for one of the signature schemes offered by the client,
+/// ECDSA and Ed25519 keys before RSA and otherwise in the configured order
+#[derive(Debug)]
+pub struct MultiResolver {
+    resolvers: Vec<FileResolver>
+}
+
+impl CertificateError {
+    pub fn new(path: &Path, kind: CertificateErrorKind) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            hostname: None,
+            kind
+        }
+    }
+
+    pub fn with_hostname(mut self, hostname: &str) -> Self {
+        self.hostname = Some(hostname.to_string());
+        self
+    }
+}
+
+impl error::Error for CertificateError {}
+
+impl Display for CertificateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.hostname {
+            Some(hostname) => write!(f, "Certificate for {}", hostname)?,
+            None => write!(f, "Default certificate")?
+        }
+        match self.path.as_os_str().is_empty() {
+            true => write!(f, ": ")?,
+            false => write!(f, " ({}): ", self.path.display())?
+        }
+
+        match &self.kind {
+            CertificateErrorKind::Read(e) => write!(f, "can't read file: {}", e),
+            CertificateErrorKind::NoCertificates => write!(f, "no certificates found"),
+            CertificateErrorKind::NoKey => write!(f, "no private key found"),
+            CertificateErrorKind::KeyMismatch => write!(f, "private key doesn't match the certificate"),
+            CertificateErrorKind::Expired(time) => write!(f, "certificate expired on {}", time),
+            CertificateErrorKind::NotYetValid(time) => write!(f, "certificate isn't valid before {}", time),
+            CertificateErrorKind::Invalid(e) => write!(f, "invalid certificate: {}", e),
+            CertificateErrorKind::Rustls(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl FileResolver {
+    pub fn new(certificate: &Path, key: &Path, ocsp: Option<OcspSource>, build: Build<'_>) -> Result<Self, CertificateError> {
+        let mut loaded = load_certified_key(certificate, key)?;
+        let mut stapler = ocsp.map(OcspStapler::new);
+        if let Some(stapler) = &mut stapler {
+            loaded.ocsp = stapler.load(&loaded)?;
+        }
+
+        let current = Arc::new(RwLock::new(Arc::new(loaded)));
+        let reloaded = Arc::new(Notify::new());
+        let tasks = [
+            build.spawn(watch(certificate.to_path_buf(), key.to_path_buf(), reloaded.clone(), current.clone())),
+            stapler.and_then(|x| build.spawn(staple(x, reloaded, current.clone())))
+        ];
+
+        Ok(Self {
+            current,
+            tasks: tasks.into_iter().flatten().collect()
+        })
+    }
+}
+
+impl Drop for FileResolver {
+    fn drop(&mut self) {
+        self.tasks.iter().for_each(JoinHandle::abort);
+    }
+}
+
+impl ResolvesServerCert for FileResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+// Client certificates of outbound connections are reloaded the same way
+impl ResolvesClientCert for FileResolver {
+    fn resolve(&self, _root_hint_subjects: &[&[u8]], _sigschemes: &[SignatureScheme]) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+impl MultiResolver {
+    pub fn new(resolvers: Vec<FileResolver>) -> Self {
+        Self {
+            resolvers
+        }
+    }
+}
+
+impl ResolvesServerCert for MultiResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let keys = self.resolvers.iter().map(|x| x.current.read().unwrap().clone()).collect::<Vec<_>>();
+        select_key(&keys, client_hello.signature_schemes())
+    }
+}
+
+fn select_key(keys: &[Arc<CertifiedKey>], schemes: &[SignatureScheme]) -> Option<Arc<CertifiedKey>> {
+    // Smaller keys and signatures for clients that support them, RSA stays the fallback
+    let preference = |key: &CertifiedKey| match key.key.algorithm() {
+        SignatureAlgorithm::ECDSA | SignatureAlgorithm::ED25519 => 0,
+        _ => 1
+    };
+
+    keys.iter()
+        .filter(|x| x.key.choose_scheme(schemes).is_some())
+        .min_by_key(|x| preference(x))
+        .or(keys.first())
+        .cloned()
+}
+
+/// Poll the modification times and swap in the new certificate, a pair failing to load keeps the old one
+async fn watch(certificate: PathBuf, key: PathBuf, reloaded: Arc<Notify>, current: Arc<RwLock<Arc<CertifiedKey>>>) {
+    let mut last = modified(&certificate, &key);
+    loop {
+        sleep(WATCH_INTERVAL).await;
+
+        let changed = modified(&certificate, &key);
+        if changed == last {
+            continue;
+        }
+        last = changed;
+
+        match load_certified_key(&certificate, &key) {
+            Ok(loaded) => {
+                *current.write().unwrap() = Arc::new(loaded);
+                reloaded.notify_one();
+                println!("Reloaded certificate {}", certificate.display());
+            },
+            Err(e) => println!("Keeping the previous certificate: {}", e)
+        }
+    }
+}
+
+/// Keep the OCSP response of the current certificate up to date, separately so a slow responder doesn't hold up reloads
+async fn staple(mut stapler: OcspStapler, reloaded: Arc<Notify>, current: Arc<RwLock<Arc<CertifiedKey>>>) {
+    let mut stapled = current.read().unwrap().cert.clone();
+    loop {
+        let certified = current.read().unwrap().clone();
+        let response = match certified.cert == stapled {
+            true => tokio::select! {
+                response = stapler.update(&certified) => response,
+                _ = reloaded.notified() => continue
+            },
+            // A response for the previous certificate doesn't apply to the new one
+            false => {
+                stapled = certified.cert.clone();
+                stapler.load(&certified).unwrap_or_else(|e| {
+                    println!("Not stapling an OCSP response: {}", e);
+                    None
+                })
+            }
+        };
+
+        if let Some(response) = response {
+            let mut current = current.write().unwrap();
+            if current.cert == certified.cert {
+                *current = Arc::new(CertifiedKey { ocsp: Some(response), ..(**current).clone() });
+            }
+        }
+
+        tokio::select! {
+            _ = sleep(WATCH_INTERVAL) => (),
+            _ = reloaded.notified() => ()
+        }
+    }
+}
+
+// Follows symlinks, so switching the live links of certbot counts as a change
+fn modified(certificate: &Path, key: &Path) -> (Option<SystemTime>, Option<SystemTime>) {
+    let modified = |path: &Path| fs::metadata(path).and_then(|x| x.modified()).ok();
+    (modified(certificate), modified(key))
+}
+
+/// Read a PEM certificate chain and check that the leaf certificate is currently valid
+pub fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, CertificateError> {
+    let certificates = read_certificates(path)?;
+    let leaf = certificates.first().ok_or(CertificateError::new(path, CertificateErrorKind::NoCertificates))?;
+    let (_, leaf) = parse_x509_certificate(leaf)
+        .map_err(|e| CertificateError::new(path, CertificateErrorKind::Invalid(e.to_string())))?;
+
+    let now = ASN1Time::now();
+    let validity = leaf.validity();
+    if now > validity.not_after {
+        return Err(CertificateError::new(path, CertificateErrorKind::Expired(validity.not_after)));
+    }
+    if now < validity.not_before {
+        return Err(CertificateError::new(path, CertificateErrorKind::NotYetValid(validity.not_before)));
+    }
+
+    Ok(certificates)
+}
+
+/// Read a PEM bundle of trusted CA certificates, expired ones are left for the verifier to reject
+pub fn load_roots(path: &Path) -> Result<RootCertStore, CertificateError> {
+    let mut roots = RootCertStore::empty();
+    for certificate in read_certificates(path)? {
+        roots.add(certificate).map_err(|e| CertificateError::new(path, CertificateErrorKind::Rustls(e)))?;
+    }
+
+    match roots.is_empty() {
+        true => Err(CertificateError::new(path, CertificateErrorKind::NoCertificates)),
+        false => Ok(roots)
+    }
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, CertificateError> {
+    let file = File::open(path).map_err(|e| CertificateError::new(path, CertificateErrorKind::Read(e)))?;
+    certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| CertificateError::new(path, CertificateErrorKind::Read(e)))
+}
+
+pub fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, CertificateError> {
+    let file = File::open(path).map_err(|e| CertificateError::new(path, CertificateErrorKind::Read(e)))?;
+    private_key(&mut BufReader::new(file))
+        .map_err(|e| CertificateError::new(path, CertificateErrorKind::Read(e)))?
+        .ok_or(CertificateError::new(path, CertificateErrorKind::NoKey))
+}
+
+/// Load a certificate chain and its private key, checking that both belong together
+pub fn load_certified_key(certificate: &Path, key: &Path) -> Result<CertifiedKey, CertificateError> {
+    let certificates = load_certificates(certificate)?;
+    let private_key = load_key(key)?;
+
+    CertifiedKey::from_der(certificates, private_key, &provider())
+        .map_err(|e| config_error(certificate, key, e))
+}
+
+/// Crypto provider used to load keys outside of a config builder
+pub fn provider() -> Arc<CryptoProvider> {
+    ServerConfig::builder().crypto_provider().clone()
+}
+
+/// Map errors from building a rustls config with the given key pair
+pub fn config_error(certificate: &Path, key: &Path, error: rustls::Error) -> CertificateError {
+    match error {
+        rustls::Error::InconsistentKeys(InconsistentKeys::KeyMismatch) => CertificateError::new(key, CertificateErrorKind::KeyMismatch),
+        e => CertificateError::new(certificate, CertificateErrorKind::Rustls(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{date_time_ymd, BasicConstraints, CertificateParams, DnType, IsCa, KeyPair, SignatureAlgorithm as KeyAlgorithm, PKCS_ECDSA_P256_SHA256, PKCS_ED25519, PKCS_RSA_SHA256};
+    use tokio_rustls::rustls::crypto::aws_lc_rs::sign::any_supported_type;
+
+    use super::*;
+
+    fn authority(name: &str, expired: bool) -> String {
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        if expired {
+            params.not_before = date_time_ymd(2000, 1, 1);
+            params.not_after = date_time_ymd(2001, 1, 1);
+        }
+        params.self_signed(&KeyPair::generate().unwrap()).unwrap().pem()
+    }
+
+    #[test]
+    fn roots_with_expired_certificate() {
+        let path = std::env::temp_dir().join(format!("rproxy-roots-{}.pem", std::process::id()));
+        fs::write(&path, authority("Old CA", true) + &authority("Current CA", false)).unwrap();
+
+        let chain = load_certificates(&path);
+        let roots = load_roots(&path);
+        let _ = fs::remove_file(&path);
+
+        // Only the leaf of a certificate chain has to be valid, a CA bundle may hold expired certificates
+        assert!(matches!(chain, Err(CertificateError { kind: CertificateErrorKind::Expired(_), .. })));
+        assert_eq!(roots.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn roots_empty() {
+        let path = std::env::temp_dir().join(format!("rproxy-roots-empty-{}.pem", std::process::id()));
+        fs::write(&path, "").unwrap();
+
+        let roots = load_roots(&path);
+        let _ = fs::remove_file(&path);
+        assert!(matches!(roots, Err(CertificateError { kind: CertificateErrorKind::NoCertificates, .. })));
+    }
+
+    fn certified_key(algorithm: &'static KeyAlgorithm) -> Arc<CertifiedKey> {
+        let key = KeyPair::generate_for(algorithm).unwrap();
+        let certificate = CertificateParams::new(vec!["localhost".to_string()]).unwrap().self_signed(&key).unwrap();
+        let key = PrivateKeyDer::try_from(key.serialize_der()).unwrap();
+        Arc::new(CertifiedKey::new(vec![certificate.der().clone()], any_supported_type(&key).unwrap()))
+    }
+
+    #[test]
+    fn key_selection() {
+        let rsa = certified_key(&PKCS_RSA_SHA256);
+        let ecdsa = certified_key(&PKCS_ECDSA_P256_SHA256);
+        let ed25519 = certified_key(&PKCS_ED25519);
+        let keys = [rsa.clone(), ecdsa.clone(), ed25519.clone()];
+        let selected = |schemes: &[SignatureScheme]| select_key(&keys, schemes).map(|x| x.key.algorithm());
+
+        // Elliptic curve keys are preferred regardless of the configured order, ties keep it
+        assert_eq!(selected(&[SignatureScheme::RSA_PSS_SHA256, SignatureScheme::ECDSA_NISTP256_SHA256, SignatureScheme::ED25519]), Some(SignatureAlgorithm::ECDSA));
+        assert_eq!(selected(&[SignatureScheme::RSA_PSS_SHA256, SignatureScheme::ED25519]), Some(SignatureAlgorithm::ED25519));
+        assert_eq!(selected(&[SignatureScheme::RSA_PSS_SHA256]), Some(SignatureAlgorithm::RSA));
+        // Nothing usable, the first configured pair is offered anyway
+        assert_eq!(selected(&[SignatureScheme::ECDSA_NISTP521_SHA512]), Some(SignatureAlgorithm::RSA));
+        assert!(select_key(&[], &[SignatureScheme::ED25519]).is_none());
+    }
+}
@@ -0,0 +1,59 @@
+use rustls_platform_verifier::BuilderVerifierExt;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::TlsConnector;
+
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::error::Error;
+use crate::settings::{self, Build};
+
+use super::certificate::{load_roots, provider, FileResolver};
+
+/// Wraps outbound connections in TLS, verifying the server against the configured CA bundle or the platform roots
+pub struct TlsClient {
+    connector: TlsConnector,
+    server_name: Option<ServerName<'static>>
+}
+
+impl TlsClient {
+    pub fn new(settings: &settings::TunnelTls, build: Build<'_>) -> Result<Self, Error> {
+        let server_name = settings.sni.clone()
+            .map(ServerName::try_from)
+            .transpose()
+            .map_err(|e| format!("Invalid TLS server name: {}", e))?;
+
+        let builder = ClientConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()?;
+        let builder = match &settings.ca {
+            Some(ca) => builder.with_root_certificates(load_roots(Path::new(ca))?),
+            None => builder.with_platform_verifier()
+        };
+
+        let mut config = match (&settings.certificate, &settings.key) {
+            (Some(certificate), Some(key)) => builder.with_client_cert_resolver(Arc::new(FileResolver::new(Path::new(certificate), Path::new(key), None, build)?)),
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err("Tunnel TLS client authentication needs both certificate and key".into())
+        };
+        config.alpn_protocols = settings.alpn.iter().flatten().map(|x| x.as_bytes().to_vec()).collect();
+
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name
+        })
+    }
+
+    /// The server name defaults to `host`, the host the connection was made to
+    pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S, host: &str) -> io::Result<TlsStream<S>> {
+        let server_name = match &self.server_name {
+            Some(server_name) => server_name.clone(),
+            None => ServerName::try_from(host.to_string()).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?
+        };
+        self.connector.connect(server_name, stream).await
+    }
+}
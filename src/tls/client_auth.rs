@@ -0,0 +1,89 @@
+use base64::prelude::*;
+
+use rustls_pemfile::crls;
+
+use sha2::{Digest, Sha256};
+
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::server::danger::ClientCertVerifier;
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+
+use x509_parser::extensions::GeneralName;
+use x509_parser::parse_x509_certificate;
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::settings::{self, ClientAuthMode};
+
+use super::{load_roots, CertificateError, CertificateErrorKind};
+
+/// Verified client certificate presented during the handshake
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+    pub subject: String,
+    pub sans: Vec<String>,
+    /// Hex encoded SHA-256 of the DER certificate
+    pub fingerprint: String,
+    pub der: CertificateDer<'static>
+}
+
+impl ClientCertificate {
+    /// PEM encoding of the certificate
+    pub fn pem(&self) -> String {
+        let encoded = BASE64_STANDARD.encode(&self.der);
+        let lines = encoded.as_bytes().chunks(64).map(|x| std::str::from_utf8(x).unwrap()).collect::<Vec<_>>();
+        format!("-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n", lines.join("\n"))
+    }
+
+    pub fn from_der(certificate: &CertificateDer<'_>) -> Option<Self> {
+        let (_, parsed) = parse_x509_certificate(certificate).ok()?;
+        let sans = parsed.subject_alternative_name().ok().flatten()
+            .map(|x| x.value.general_names.iter().filter_map(|name| match name {
+                GeneralName::DNSName(x) => Some(x.to_string()),
+                GeneralName::RFC822Name(x) => Some(x.to_string()),
+                GeneralName::URI(x) => Some(x.to_string()),
+                GeneralName::IPAddress(x) => ip_address(x),
+                _ => None
+            }).collect())
+            .unwrap_or_default();
+
+        Some(Self {
+            subject: parsed.subject().to_string(),
+            sans,
+            fingerprint: Sha256::digest(certificate).iter().map(|x| format!("{:02x}", x)).collect(),
+            der: certificate.clone().into_owned()
+        })
+    }
+}
+
+/// Build the verifier for client certificates issued by the configured CA bundle
+pub fn create_verifier(settings: &settings::ClientAuth) -> Result<Arc<dyn ClientCertVerifier>, CertificateError> {
+    let ca = Path::new(&settings.ca);
+    let roots = load_roots(ca)?;
+
+    let mut revocations = Vec::new();
+    for path in settings.crl.iter().flatten().map(Path::new) {
+        let file = File::open(path).map_err(|e| CertificateError::new(path, CertificateErrorKind::Read(e)))?;
+        for crl in crls(&mut BufReader::new(file)) {
+            revocations.push(crl.map_err(|e| CertificateError::new(path, CertificateErrorKind::Read(e)))?);
+        }
+    }
+
+    let mut builder = WebPkiClientVerifier::builder(Arc::new(roots)).with_crls(revocations);
+    if settings.mode == Some(ClientAuthMode::Optional) {
+        builder = builder.allow_unauthenticated();
+    }
+
+    builder.build().map_err(|e| CertificateError::new(ca, CertificateErrorKind::Invalid(e.to_string())))
+}
+
+fn ip_address(bytes: &[u8]) -> Option<String> {
+    match bytes.len() {
+        4 => Some(std::net::Ipv4Addr::from(<[u8; 4]>::try_from(bytes).ok()?).to_string()),
+        16 => Some(std::net::Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?).to_string()),
+        _ => None
+    }
+}
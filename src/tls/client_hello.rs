@@ -0,0 +1,446 @@
+use md5::Md5;
+
+use sha2::{Digest, Sha256};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use tokio_rustls::rustls::CipherSuite;
+
+use std::fmt;
+use std::io::{self, ErrorKind};
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const EXTENSION_SUPPORTED_GROUPS: u16 = 10;
+const EXTENSION_POINT_FORMATS: u16 = 11;
+const EXTENSION_SIGNATURE_ALGORITHMS: u16 = 13;
+const EXTENSION_ALPN: u16 = 16;
+const EXTENSION_SUPPORTED_VERSIONS: u16 = 43;
+pub const DEFAULT_MAX_CLIENT_HELLO: usize = 64 * 1024;
+
+/// Fields of a ClientHello which are needed before deciding how to handle the connection
+#[derive(Debug, Default)]
+pub struct ClientHello {
+    pub server_name: Option<String>,
+    pub version: u16,
+    pub cipher_suites: Vec<u16>,
+    pub extensions: Vec<u16>,
+    pub groups: Vec<u16>,
+    pub point_formats: Vec<u8>,
+    pub signature_algorithms: Vec<u16>,
+    pub alpn: Vec<Vec<u8>>,
+    pub supported_versions: Vec<u16>
+}
+
+/// Client fingerprints and offered parameters, GREASE values are left out
+#[derive(Debug, Clone)]
+pub struct TlsFingerprint {
+    pub ja3: String,
+    pub ja4: String,
+    pub alpn: Vec<String>,
+    pub cipher_suites: Vec<String>
+}
+
+/// Reasons the start of a connection isn't an acceptable ClientHello
+#[derive(Debug, Clone, Copy)]
+pub enum ClientHelloError {
+    NotTls,
+    TooLarge,
+    Malformed
+}
+
+/// Read and parse the ClientHello of at most `max` bytes, the raw bytes are appended to `buf` so they can be replayed
+pub async fn read_client_hello<S: AsyncRead + Unpin>(stream: &mut S, buf: &mut Vec<u8>, max: usize) -> io::Result<ClientHello> {
+    let message = read_client_hello_message(stream, buf, max).await?;
+    ClientHello::parse(&message).ok_or(ClientHelloError::Malformed.into())
+}
+
+/// Read the records carrying the ClientHello of at most `max` bytes and return the body of the message, leaving the parsing to the caller
+pub async fn read_client_hello_message<S: AsyncRead + Unpin>(stream: &mut S, buf: &mut Vec<u8>, max: usize) -> io::Result<Vec<u8>> {
+    let mut handshake = Vec::new();
+    loop {
+        let mut header = [0; 5];
+        stream.read_exact(&mut header).await?;
+        buf.extend_from_slice(&header);
+        if header[0] != CONTENT_TYPE_HANDSHAKE {
+            return Err(ClientHelloError::NotTls.into());
+        }
+
+        let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+        if handshake.len() + length > max {
+            return Err(ClientHelloError::TooLarge.into());
+        }
+
+        let start = handshake.len();
+        handshake.resize(start + length, 0);
+        stream.read_exact(&mut handshake[start..]).await?;
+        buf.extend_from_slice(&handshake[start..]);
+
+        // A ClientHello may be fragmented over multiple records
+        if handshake.len() >= 4 {
+            let message_length = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            if message_length + 4 > max {
+                return Err(ClientHelloError::TooLarge.into());
+            }
+            if handshake.len() >= message_length + 4 {
+                if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+                    return Err(ClientHelloError::NotTls.into());
+                }
+                handshake.truncate(message_length + 4);
+                handshake.drain(..4);
+                return Ok(handshake);
+            }
+        }
+    }
+}
+
+impl ClientHello {
+    /// Fields of a ClientHello message body, `None` when it's malformed
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let mut reader = Reader(data);
+        let mut hello = ClientHello {
+            version: reader.u16()?,
+            ..Default::default()
+        };
+
+        reader.take(32)?;
+        let session_id = reader.u8()? as usize;
+        reader.take(session_id)?;
+        hello.cipher_suites = Reader(reader.vec16()?).u16s()?;
+
+        let compression = reader.u8()? as usize;
+        reader.take(compression)?;
+
+        // The extensions block may be omitted entirely
+        if reader.0.is_empty() {
+            return Some(hello);
+        }
+
+        let mut extensions = Reader(reader.vec16()?);
+        while !extensions.0.is_empty() {
+            let kind = extensions.u16()?;
+            let mut data = Reader(extensions.vec16()?);
+            hello.extensions.push(kind);
+
+            match kind {
+                EXTENSION_SERVER_NAME => {
+                    let mut names = Reader(data.vec16()?);
+                    while !names.0.is_empty() {
+                        let name_type = names.u8()?;
+                        let name = names.vec16()?;
+                        if name_type == 0 {
+                            hello.server_name = Some(String::from_utf8(name.to_vec()).ok()?.to_ascii_lowercase());
+                        }
+                    }
+                },
+                EXTENSION_SUPPORTED_GROUPS => hello.groups = Reader(data.vec16()?).u16s()?,
+                EXTENSION_POINT_FORMATS => hello.point_formats = data.vec8()?.to_vec(),
+                EXTENSION_SIGNATURE_ALGORITHMS => hello.signature_algorithms = Reader(data.vec16()?).u16s()?,
+                EXTENSION_SUPPORTED_VERSIONS => hello.supported_versions = Reader(data.vec8()?).u16s()?,
+                EXTENSION_ALPN => {
+                    let mut protocols = Reader(data.vec16()?);
+                    while !protocols.0.is_empty() {
+                        hello.alpn.push(protocols.vec8()?.to_vec());
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        Some(hello)
+    }
+
+    pub fn fingerprint(&self) -> TlsFingerprint {
+        TlsFingerprint {
+            ja3: self.ja3(),
+            ja4: self.ja4(),
+            alpn: self.alpn.iter().map(|x| String::from_utf8_lossy(x).into_owned()).collect(),
+            cipher_suites: without_grease(&self.cipher_suites)
+                .map(|x| CipherSuite::from(x).as_str().map(str::to_string).unwrap_or(format!("0x{:04x}", x)))
+                .collect()
+        }
+    }
+
+    /// MD5 of version, ciphers, extensions, groups and point formats as decimal lists
+    pub fn ja3(&self) -> String {
+        let ja3 = [
+            self.version.to_string(),
+            decimal(without_grease(&self.cipher_suites)),
+            decimal(without_grease(&self.extensions)),
+            decimal(without_grease(&self.groups)),
+            decimal(self.point_formats.iter().map(|x| *x as u16))
+        ].join(",");
+
+        hex(&Md5::digest(ja3.as_bytes()))
+    }
+
+    /// JA4 of a TLS over TCP client: version, SNI, counts and ALPN, then hashes of the sorted ciphers and extensions
+    pub fn ja4(&self) -> String {
+        let version = without_grease(&self.supported_versions).max().unwrap_or(self.version);
+        let version = match version {
+            0x0304 => "13",
+            0x0303 => "12",
+            0x0302 => "11",
+            0x0301 => "10",
+            0x0300 => "s3",
+            _ => "00"
+        };
+
+        let ciphers = without_grease(&self.cipher_suites).collect::<Vec<_>>();
+        let extensions = without_grease(&self.extensions).collect::<Vec<_>>();
+        let alpn = match self.alpn.first().map(Vec::as_slice) {
+            Some([first, .., last]) if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() => format!("{}{}", *first as char, *last as char),
+            Some([single]) if single.is_ascii_alphanumeric() => format!("{}{}", *single as char, *single as char),
+            Some(value) if !value.is_empty() => {
+                let value = hex(value);
+                format!("{}{}", &value[..1], &value[value.len() - 1..])
+            },
+            _ => "00".to_string()
+        };
+
+        let a = format!(
+            "t{}{}{:02}{:02}{}",
+            version,
+            if self.server_name.is_some() { 'd' } else { 'i' },
+            ciphers.len().min(99),
+            extensions.len().min(99),
+            alpn
+        );
+
+        let mut sorted_ciphers = ciphers.iter().map(|x| format!("{:04x}", x)).collect::<Vec<_>>();
+        sorted_ciphers.sort();
+        let b = truncated_hash(&sorted_ciphers.join(","));
+
+        let mut sorted_extensions = extensions.iter()
+            .filter(|x| **x != EXTENSION_SERVER_NAME && **x != EXTENSION_ALPN)
+            .map(|x| format!("{:04x}", x))
+            .collect::<Vec<_>>();
+        sorted_extensions.sort();
+        let signature_algorithms = self.signature_algorithms.iter().map(|x| format!("{:04x}", x)).collect::<Vec<_>>();
+        let c = match (sorted_extensions.is_empty(), signature_algorithms.is_empty()) {
+            (true, _) => truncated_hash(""),
+            (false, true) => truncated_hash(&sorted_extensions.join(",")),
+            (false, false) => truncated_hash(&format!("{}_{}", sorted_extensions.join(","), signature_algorithms.join(",")))
+        };
+
+        format!("{}_{}_{}", a, b, c)
+    }
+}
+
+// GREASE values look like 0x?a?a with both bytes equal (RFC 8701)
+fn without_grease(values: &[u16]) -> impl Iterator<Item = u16> + '_ {
+    values.iter().copied().filter(|x| x & 0x0f0f != 0x0a0a || x >> 8 != x & 0xff)
+}
+
+fn decimal(values: impl Iterator<Item = u16>) -> String {
+    values.map(|x| x.to_string()).collect::<Vec<_>>().join("-")
+}
+
+fn truncated_hash(value: &str) -> String {
+    match value.is_empty() {
+        true => "000000000000".to_string(),
+        false => hex(&Sha256::digest(value.as_bytes()))[..12].to_string()
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+impl fmt::Display for ClientHelloError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotTls => write!(f, "not a TLS ClientHello"),
+            Self::TooLarge => write!(f, "ClientHello too large"),
+            Self::Malformed => write!(f, "malformed ClientHello")
+        }
+    }
+}
+
+impl std::error::Error for ClientHelloError {}
+
+impl From<ClientHelloError> for io::Error {
+    fn from(e: ClientHelloError) -> Self {
+        io::Error::new(ErrorKind::InvalidData, e)
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let length = self.u8()? as usize;
+        self.take(length)
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let length = self.u16()? as usize;
+        self.take(length)
+    }
+
+    /// Consume the remaining data as a list of u16 values
+    fn u16s(&mut self) -> Option<Vec<u16>> {
+        let mut values = Vec::new();
+        while !self.0.is_empty() {
+            values.push(self.u16()?);
+        }
+        Some(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Chrome example from the JA4 reference implementation, with GREASE values added the way browsers send them
+    fn chrome() -> ClientHello {
+        ClientHello {
+            server_name: Some("example.com".to_string()),
+            version: 0x0303,
+            cipher_suites: vec![
+                0x2a2a, 0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc013, 0xc014, 0x009c, 0x009d, 0x002f, 0x0035
+            ],
+            extensions: vec![
+                0x0a0a, 0x001b, 0x0000, 0x0033, 0x0010, 0x4469, 0x0017, 0x002d, 0x000d, 0x0005, 0x0023, 0x0012, 0x002b, 0xff01, 0x000b, 0x000a, 0x0015, 0x3a3a
+            ],
+            groups: vec![0x4a4a, 0x001d, 0x0017, 0x0018],
+            point_formats: vec![0],
+            signature_algorithms: vec![0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601],
+            alpn: vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            supported_versions: vec![0x7a7a, 0x0304, 0x0303]
+        }
+    }
+
+    #[test]
+    fn ja4_reference() {
+        assert_eq!(chrome().ja4(), "t13d1516h2_8daaf6152771_e5627efa2ab1");
+    }
+
+    #[test]
+    fn ja4_ignores_grease_and_order() {
+        let mut hello = chrome();
+        hello.cipher_suites.retain(|x| *x != 0x2a2a);
+        hello.cipher_suites.reverse();
+        hello.extensions.retain(|x| *x != 0x0a0a && *x != 0x3a3a);
+        hello.extensions.reverse();
+        hello.supported_versions.retain(|x| *x != 0x7a7a);
+        assert_eq!(hello.ja4(), "t13d1516h2_8daaf6152771_e5627efa2ab1");
+    }
+
+    #[test]
+    fn ja4_sni_and_alpn_only_in_prefix() {
+        // Without SNI and ALPN the counts and flags change, the extension hash doesn't
+        let mut hello = chrome();
+        hello.server_name = None;
+        hello.extensions.retain(|x| *x != EXTENSION_SERVER_NAME && *x != EXTENSION_ALPN);
+        hello.alpn.clear();
+        assert_eq!(hello.ja4(), "t13i151400_8daaf6152771_e5627efa2ab1");
+    }
+
+    #[test]
+    fn ja4_alpn() {
+        let alpn = |value: &[u8]| {
+            let hello = ClientHello {
+                alpn: vec![value.to_vec()],
+                ..chrome()
+            };
+            hello.ja4()[8..10].to_string()
+        };
+
+        assert_eq!(alpn(b"http/1.1"), "h1");
+        assert_eq!(alpn(b"a"), "aa");
+        // Non alphanumeric first or last bytes are replaced by the first and last hex digits
+        assert_eq!(alpn(&[0xab, 0xcd]), "ad");
+        assert_eq!(alpn(&[0x30, 0xab]), "3b");
+        assert_eq!(alpn(b"h2/"), "6f");
+    }
+
+    #[test]
+    fn ja3_reference() {
+        // Example from the JA3 reference implementation, which has no GREASE
+        let hello = ClientHello {
+            version: 769,
+            cipher_suites: vec![47, 53, 5, 10, 49161, 49162, 49171, 49172, 50, 56, 19, 4],
+            extensions: vec![0, 10, 11],
+            groups: vec![23, 24, 25],
+            point_formats: vec![0],
+            ..Default::default()
+        };
+        assert_eq!(hello.ja3(), "ada70206e40642a3e4461f35503241d5");
+
+        // GREASE values don't change it
+        let hello = ClientHello {
+            cipher_suites: [0x0a0a].into_iter().chain(hello.cipher_suites.iter().copied()).collect(),
+            extensions: [0xfafa].into_iter().chain(hello.extensions.iter().copied()).collect(),
+            groups: [0x1a1a].into_iter().chain(hello.groups.iter().copied()).collect(),
+            ..hello
+        };
+        assert_eq!(hello.ja3(), "ada70206e40642a3e4461f35503241d5");
+    }
+
+    #[test]
+    fn grease() {
+        let values = [0x0a0a, 0x1a1a, 0xfafa, 0x0a1a, 0x1301, 0x0a0b];
+        assert_eq!(without_grease(&values).collect::<Vec<_>>(), vec![0x0a1a, 0x1301, 0x0a0b]);
+    }
+
+    #[tokio::test]
+    async fn parse_fragmented() {
+        let mut extensions = Vec::new();
+        // server_name
+        extensions.extend([0x00, 0x00, 0x00, 0x10, 0x00, 0x0e, 0x00, 0x00, 0x0b]);
+        extensions.extend(b"Example.COM");
+        // application_layer_protocol_negotiation
+        extensions.extend([0x00, 0x10, 0x00, 0x05, 0x00, 0x03, 0x02, b'h', b'2']);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend([0; 32]);
+        body.extend([0x00, 0x00, 0x04, 0x13, 0x01, 0x0a, 0x0a, 0x01, 0x00]);
+        body.extend((extensions.len() as u16).to_be_bytes());
+        body.extend(&extensions);
+
+        let mut message = vec![HANDSHAKE_CLIENT_HELLO, 0];
+        message.extend((body.len() as u16).to_be_bytes());
+        message.extend(&body);
+
+        // Split over two records
+        let mut records = Vec::new();
+        for fragment in [&message[..10], &message[10..]] {
+            records.extend([CONTENT_TYPE_HANDSHAKE, 0x03, 0x01]);
+            records.extend((fragment.len() as u16).to_be_bytes());
+            records.extend(fragment);
+        }
+
+        let mut buf = Vec::new();
+        let hello = read_client_hello(&mut records.as_slice(), &mut buf, DEFAULT_MAX_CLIENT_HELLO).await.unwrap();
+        assert_eq!(buf, records);
+        assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+        assert_eq!(hello.alpn, vec![b"h2".to_vec()]);
+        assert_eq!(hello.cipher_suites, vec![0x1301, 0x0a0a]);
+        assert_eq!(hello.extensions, vec![EXTENSION_SERVER_NAME, EXTENSION_ALPN]);
+
+        // Truncated extensions can't be parsed, reading the message still works
+        let mut truncated = message.clone();
+        truncated.truncate(message.len() - 2);
+        assert!(ClientHello::parse(&truncated[4..]).is_none());
+    }
+}
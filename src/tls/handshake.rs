@@ -0,0 +1,144 @@
+use tokio::time::timeout;
+
+use tokio_rustls::rustls;
+
+use std::fmt::Write;
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::settings;
+
+use super::client_hello::{ClientHelloError, DEFAULT_MAX_CLIENT_HELLO};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const REASONS: [&str; 9] = ["timeout", "client_hello_too_large", "not_tls", "closed", "alert", "incompatible", "certificate", "protocol", "other"];
+
+static HANDSHAKES: Outcomes = Outcomes::new();
+// Passthrough only reads the ClientHello, the handshake itself completes upstream
+static PASSTHROUGH: Outcomes = Outcomes::new();
+
+/// Completed and failed attempts, failures by reason
+struct Outcomes {
+    completed: AtomicU64,
+    failed: [AtomicU64; REASONS.len()]
+}
+
+/// Reasons a handshake failed, the discriminant indexes `REASONS`
+#[derive(Debug, Clone, Copy)]
+enum Failure {
+    Timeout,
+    TooLarge,
+    NotTls,
+    Closed,
+    Alert,
+    Incompatible,
+    Certificate,
+    Protocol,
+    Other
+}
+
+/// Bounds on the resources a client can hold before completing the handshake
+#[derive(Debug, Clone, Copy)]
+pub struct HandshakeLimits {
+    pub timeout: Duration,
+    pub max_client_hello: usize
+}
+
+impl HandshakeLimits {
+    pub fn new(settings: &settings::HandshakeLimits) -> Self {
+        Self {
+            timeout: settings.handshake_timeout.map(Duration::from_millis).unwrap_or(DEFAULT_TIMEOUT),
+            max_client_hello: settings.max_client_hello.unwrap_or(DEFAULT_MAX_CLIENT_HELLO)
+        }
+    }
+
+    /// Run a handshake within the timeout and count its outcome
+    pub async fn handshake<T>(&self, handshake: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+        HANDSHAKES.count(self.limit(handshake).await)
+    }
+
+    /// Read the ClientHello of a passthrough connection within the timeout and count its outcome
+    pub async fn passthrough<T>(&self, client_hello: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+        PASSTHROUGH.count(self.limit(client_hello).await)
+    }
+
+    async fn limit<T>(&self, handshake: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+        timeout(self.timeout, handshake).await
+            .unwrap_or_else(|_| Err(io::Error::new(ErrorKind::TimedOut, "TLS handshake timed out")))
+    }
+}
+
+impl Outcomes {
+    const fn new() -> Self {
+        Self {
+            completed: AtomicU64::new(0),
+            failed: [const { AtomicU64::new(0) }; REASONS.len()]
+        }
+    }
+
+    fn count<T>(&self, result: io::Result<T>) -> io::Result<T> {
+        match &result {
+            Ok(_) => self.completed.fetch_add(1, Ordering::Relaxed),
+            Err(e) => self.failed[classify(e) as usize].fetch_add(1, Ordering::Relaxed)
+        };
+        result
+    }
+
+    fn write(&self, out: &mut String, completed: (&str, &str), failed: (&str, &str)) {
+        let _ = writeln!(out, "# HELP {} {}", completed.0, completed.1);
+        let _ = writeln!(out, "# TYPE {} counter", completed.0);
+        let _ = writeln!(out, "{} {}", completed.0, self.completed.load(Ordering::Relaxed));
+
+        let _ = writeln!(out, "# HELP {} {}", failed.0, failed.1);
+        let _ = writeln!(out, "# TYPE {} counter", failed.0);
+        for (reason, count) in REASONS.iter().zip(&self.failed) {
+            let _ = writeln!(out, "{}{{reason=\"{}\"}} {}", failed.0, reason, count.load(Ordering::Relaxed));
+        }
+    }
+}
+
+fn classify(error: &io::Error) -> Failure {
+    if error.kind() == ErrorKind::TimedOut {
+        return Failure::Timeout;
+    }
+
+    if let Some(error) = error.get_ref().and_then(|x| x.downcast_ref::<ClientHelloError>()) {
+        return match error {
+            ClientHelloError::TooLarge => Failure::TooLarge,
+            ClientHelloError::NotTls => Failure::NotTls,
+            ClientHelloError::Malformed => Failure::Protocol
+        };
+    }
+
+    // tokio-rustls passes errors of the TLS state machine on as the source of an io::Error
+    if let Some(error) = error.get_ref().and_then(|x| x.downcast_ref::<rustls::Error>()) {
+        return match error {
+            rustls::Error::AlertReceived(_) => Failure::Alert,
+            rustls::Error::PeerIncompatible(_) => Failure::Incompatible,
+            rustls::Error::NoCertificatesPresented | rustls::Error::InvalidCertificate(_) => Failure::Certificate,
+            rustls::Error::InvalidMessage(_)
+            | rustls::Error::PeerMisbehaved(_)
+            | rustls::Error::InappropriateMessage { .. }
+            | rustls::Error::InappropriateHandshakeMessage { .. } => Failure::Protocol,
+            _ => Failure::Other
+        };
+    }
+
+    match error.kind() {
+        ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe => Failure::Closed,
+        _ => Failure::Other
+    }
+}
+
+/// Handshake and passthrough counters in the Prometheus text format
+pub fn write_metrics(out: &mut String) {
+    HANDSHAKES.write(out,
+        ("rproxy_tls_handshakes_total", "Completed TLS handshakes"),
+        ("rproxy_tls_handshake_failures_total", "Failed TLS handshakes by reason"));
+    PASSTHROUGH.write(out,
+        ("rproxy_tls_passthrough_total", "ClientHellos read for passthrough"),
+        ("rproxy_tls_passthrough_failures_total", "Failed passthrough ClientHello reads by reason"));
+}
@@ -0,0 +1,405 @@
+use async_trait::async_trait;
+
+use ktls::{config_ktls_server, CorkStream};
+
+pub mod acme;
+mod alpn;
+mod certificate;
+mod client;
+mod client_auth;
+mod client_hello;
+pub mod handshake;
+pub mod ocsp;
+mod passthrough;
+mod protocols;
+pub mod sessions;
+
+pub use alpn::*;
+pub use certificate::*;
+pub use client::*;
+pub use client_auth::*;
+pub use client_hello::TlsFingerprint;
+pub use passthrough::*;
+use client_hello::{read_client_hello_message, ClientHello};
+use handshake::HandshakeLimits;
+use protocols::create_provider;
+
+use tokio_rustls::rustls::server::danger::ClientCertVerifier;
+use tokio_rustls::rustls::server::{Acceptor, ResolvesServerCert, WebPkiClientVerifier};
+use tokio_rustls::{server, TlsAcceptor, LazyConfigAcceptor};
+use tokio_rustls::rustls::{ProtocolVersion, ServerConfig, ServerConnection};
+
+use wildmatch::WildMatch;
+
+use std::error::Error;
+use std::fmt::Display;
+use std::io;
+use std::string::FromUtf8Error;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use acme::AcmeResolver;
+use ocsp::{HttpOcspClient, OcspFetcher, OcspSource};
+use sessions::Sessions;
+
+use crate::handler::{SendableHandler, Handler, Context};
+use crate::io::{ProxyStream, SendableAsyncStream};
+use crate::settings::{self, Build};
+
+pub struct SniHandler {
+    hostname: WildMatch,
+    handler: SendableHandler,
+    ktls: Option<bool>,
+    config: Arc<ServerConfig>
+}
+
+pub struct TlsHandler {
+    acceptor: TlsAcceptor,
+    handler: SendableHandler,
+    ktls: bool,
+    challenge: bool,
+    fingerprints: bool,
+    limits: HandshakeLimits
+}
+
+pub struct LazyTlsHandler {
+    handler: SendableHandler,
+    ktls: bool,
+    sni: Vec<SniHandler>,
+    config: Arc<ServerConfig>,
+    fingerprints: bool,
+    limits: HandshakeLimits
+}
+
+/// Failure to build a TLS handler, from its certificates or from settings which can't be applied
+#[derive(Debug)]
+pub enum TlsError {
+    Certificate(CertificateError),
+    Settings(TlsSettingsError)
+}
+
+#[derive(Debug)]
+pub enum TlsSettingsErrorKind {
+    NotConfigured,
+    Conflict(&'static str),
+    Protocols(String)
+}
+
+/// TLS settings of a handler which can't be used, the hostname names the SNI handler they belong to
+#[derive(Debug)]
+pub struct TlsSettingsError {
+    pub hostname: Option<String>,
+    pub kind: TlsSettingsErrorKind
+}
+
+type ServerTlsStream = server::TlsStream<CorkStream<ProxyStream>>;
+
+impl TlsError {
+    pub fn with_hostname(self, hostname: &str) -> Self {
+        match self {
+            TlsError::Certificate(e) => TlsError::Certificate(e.with_hostname(hostname)),
+            TlsError::Settings(e) => TlsError::Settings(TlsSettingsError { hostname: Some(hostname.to_string()), ..e })
+        }
+    }
+}
+
+impl Error for TlsError {}
+
+impl Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsError::Certificate(e) => e.fmt(f),
+            TlsError::Settings(e) => e.fmt(f)
+        }
+    }
+}
+
+impl From<CertificateError> for TlsError {
+    fn from(error: CertificateError) -> Self {
+        TlsError::Certificate(error)
+    }
+}
+
+impl From<TlsSettingsError> for TlsError {
+    fn from(error: TlsSettingsError) -> Self {
+        TlsError::Settings(error)
+    }
+}
+
+impl TlsSettingsError {
+    fn new(kind: TlsSettingsErrorKind) -> Self {
+        Self {
+            hostname: None,
+            kind
+        }
+    }
+}
+
+impl Display for TlsSettingsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.hostname {
+            Some(hostname) => write!(f, "TLS settings for {}: ", hostname)?,
+            None => write!(f, "Default TLS settings: ")?
+        }
+
+        match &self.kind {
+            TlsSettingsErrorKind::NotConfigured => write!(f, "either certificate and key or acme must be configured"),
+            TlsSettingsErrorKind::Conflict(e) => write!(f, "{}", e),
+            TlsSettingsErrorKind::Protocols(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl SniHandler {
+    pub fn new(settings: &settings::SniHandler, handler: SendableHandler, build: Build<'_>) -> Result<Self, TlsError> {
+        let resolver = create_resolver(settings.certificate.as_deref(), settings.key.as_deref(), settings.ocsp_response.as_deref(), settings.certificates.as_ref(), settings.acme.as_ref(), settings.ocsp.as_ref(), build)
+            .map_err(|e| e.with_hostname(&settings.hostname))?;
+        let config = create_config(resolver, settings.client_auth.as_ref(), &settings.protocols, handler.alpn_protocols(), true, build.sessions())
+            .map_err(|e| e.with_hostname(&settings.hostname))?;
+
+        Ok(Self {
+            hostname: WildMatch::new(&settings.hostname),
+            handler,
+            ktls: settings.ktls,
+            config: Arc::new(config)
+        })
+    }
+}
+
+impl TlsHandler {
+    pub fn new(settings: &settings::Tls, handler: SendableHandler, build: Build<'_>) -> Result<Self, TlsError> {
+        let ktls = settings.ktls.unwrap_or(false);
+        let resolver = create_resolver(settings.certificate.as_deref(), settings.key.as_deref(), settings.ocsp_response.as_deref(), settings.certificates.as_ref(), settings.acme.as_ref(), settings.ocsp.as_ref(), build)?;
+        let config = create_config(resolver, settings.client_auth.as_ref(), &settings.protocols, handler.alpn_protocols(), ktls, build.sessions())?;
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            ktls,
+            handler,
+            challenge: settings.acme.as_ref().is_some_and(|x| x.challenge == Some(settings::AcmeChallenge::TlsAlpn01)),
+            fingerprints: settings.handler.uses_fingerprints(),
+            limits: HandshakeLimits::new(&settings.limits)
+        })
+    }
+
+    /// Complete the handshake, `None` when the connection only answered a TLS-ALPN-01 challenge
+    async fn accept(&self, stream: ProxyStream, ctx: &mut Context) -> io::Result<Option<ServerTlsStream>> {
+        let stream = read_client_hello_limited(stream, ctx, self.limits.max_client_hello, self.fingerprints).await?;
+
+        // The ClientHello has to be inspected first to answer TLS-ALPN-01 challenges
+        Ok(match self.challenge {
+            true => {
+                let acceptor = LazyConfigAcceptor::new(Acceptor::default(), CorkStream::new(stream)).await?;
+                match acme::accept_challenge(acceptor).await? {
+                    Some(acceptor) => Some(acceptor.into_stream(self.acceptor.config().clone()).await?),
+                    None => None
+                }
+            },
+            false => Some(self.acceptor.accept(CorkStream::new(stream)).await?)
+        })
+    }
+}
+
+impl LazyTlsHandler {
+    pub fn new(
+        settings: &settings::Tls,
+        handler: SendableHandler,
+        sni: Vec<SniHandler>,
+        build: Build<'_>,
+    ) -> Result<Self, TlsError> {
+        let ktls = settings.ktls.unwrap_or(false);
+        let resolver = create_resolver(settings.certificate.as_deref(), settings.key.as_deref(), settings.ocsp_response.as_deref(), settings.certificates.as_ref(), settings.acme.as_ref(), settings.ocsp.as_ref(), build)?;
+        let config = create_config(resolver, settings.client_auth.as_ref(), &settings.protocols, handler.alpn_protocols(), ktls, build.sessions())?;
+
+        Ok(Self {
+            ktls,
+            handler,
+            fingerprints: settings.handler.uses_fingerprints() || settings.sni.iter().any(|x| x.handler.uses_fingerprints()),
+            sni,
+            config: Arc::new(config),
+            limits: HandshakeLimits::new(&settings.limits)
+        })
+    }
+
+    /// Complete the handshake with the config of the matching SNI handler, which handles the connection next
+    async fn accept(&self, stream: ProxyStream, ctx: &mut Context) -> io::Result<Option<(ServerTlsStream, &SendableHandler, bool)>> {
+        let stream = read_client_hello_limited(stream, ctx, self.limits.max_client_hello, self.fingerprints).await?;
+        let acceptor = LazyConfigAcceptor::new(Acceptor::default(), CorkStream::new(stream)).await?;
+        let Some(acceptor) = acme::accept_challenge(acceptor).await? else {
+            return Ok(None);
+        };
+
+        let (config, handler, ktls) = if let Some(sni) = self
+            .sni
+            .iter()
+            .find(|s| acceptor.client_hello().server_name().is_some_and(|x| s.hostname.matches(x)))
+        {
+            (&sni.config, &sni.handler, sni.ktls.unwrap_or(self.ktls))
+        } else {
+            (&self.config, &self.handler, self.ktls)
+        };
+
+        let stream = acceptor.into_stream(config.clone()).await?;
+        Ok(Some((stream, handler, ktls)))
+    }
+}
+
+#[async_trait]
+impl Handler for TlsHandler {
+    async fn handle(&self, stream: ProxyStream, mut ctx: Context) -> Result<(), Box<dyn Error>> {
+        let Some(stream) = self.limits.handshake(self.accept(stream, &mut ctx)).await? else {
+            return Ok(());
+        };
+        update_context(&mut ctx, stream.get_ref().1)?;
+
+        let stream = into_proxy_stream(stream, self.ktls).await?;
+        self.handler.handle(stream, ctx).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Handler for LazyTlsHandler {
+    async fn handle(&self, stream: ProxyStream, mut ctx: Context) -> Result<(), Box<dyn Error>> {
+        let Some((stream, handler, ktls)) = self.limits.handshake(self.accept(stream, &mut ctx)).await? else {
+            return Ok(());
+        };
+        update_context(&mut ctx, stream.get_ref().1)?;
+
+        let stream = into_proxy_stream(stream, ktls).await?;
+        handler.handle(stream, ctx).await?;
+        Ok(())
+    }
+}
+
+/// Read the ClientHello within the size limit and replay it to the handshake, fingerprinting it only when something uses
+/// the fingerprint. rustls has the final say on whether it's acceptable, one which can't be fingerprinted goes without.
+async fn read_client_hello_limited(mut stream: ProxyStream, ctx: &mut Context, max: usize, fingerprint: bool) -> io::Result<ProxyStream> {
+    let mut buf = Vec::new();
+    let message = read_client_hello_message(&mut stream, &mut buf, max).await?;
+    if fingerprint {
+        ctx.tls_fingerprint = ClientHello::parse(&message).map(|x| x.fingerprint());
+    }
+    Ok(ProxyStream::new_prefixed(buf, stream))
+}
+
+/// Hand the connection to the inner handler, TLS 1.3 early data is never accepted since it would only be
+/// readable once the handshake completed, which removes the latency it saves while keeping its replay risk
+async fn into_proxy_stream(stream: ServerTlsStream, ktls: bool) -> Result<ProxyStream, Box<dyn Error>> {
+    let stream: SendableAsyncStream = match ktls {
+        true => Box::pin(config_ktls_server(stream).await?),
+        false => Box::pin(stream)
+    };
+    Ok(ProxyStream::new_dynamic(Box::pin(stream)))
+}
+
+/// Record the negotiated session parameters for the inner handler
+fn update_context(ctx: &mut Context, conn: &ServerConnection) -> Result<(), FromUtf8Error> {
+    ctx.secure = true;
+    ctx.alpn = conn.alpn_protocol().map(|s| String::from_utf8(s.to_vec())).transpose()?;
+    ctx.server_name = conn.server_name().map(str::to_string);
+    ctx.tls_version = conn.protocol_version().map(|x| match x {
+        ProtocolVersion::TLSv1_2 => "TLSv1.2".to_string(),
+        ProtocolVersion::TLSv1_3 => "TLSv1.3".to_string(),
+        x => format!("{:?}", x)
+    });
+    ctx.cipher = conn.negotiated_cipher_suite().and_then(|x| x.suite().as_str()).map(str::to_string);
+    ctx.client_certificate = conn.peer_certificates().and_then(|x| x.first()).and_then(ClientCertificate::from_der);
+    Ok(())
+}
+
+/// Certificate source for a handler, either static files or an ACME managed certificate
+fn create_resolver(
+    certificate: Option<&str>,
+    key: Option<&str>,
+    ocsp_response: Option<&str>,
+    certificates: Option<&Vec<settings::CertificatePair>>,
+    acme: Option<&settings::Acme>,
+    ocsp: Option<&settings::Ocsp>,
+    build: Build<'_>,
+) -> Result<Arc<dyn ResolvesServerCert>, TlsError> {
+    // Certificates obtained from an ACME server are never stapled, certificate files would never be used
+    if acme.is_some() {
+        let conflict = match (certificate.is_some() || key.is_some() || certificates.is_some(), ocsp.is_some() || ocsp_response.is_some()) {
+            (true, _) => Some("certificate, key and certificates can't be combined with acme"),
+            (_, true) => Some("ocsp and ocsp_response can't be combined with acme"),
+            _ => None
+        };
+        if let Some(conflict) = conflict {
+            return Err(TlsSettingsError::new(TlsSettingsErrorKind::Conflict(conflict)).into());
+        }
+    }
+
+    let pairs = match (certificate, key) {
+        (Some(certificate), Some(key)) => vec![(certificate, key, ocsp_response)],
+        (None, None) => vec![],
+        _ => return Err(TlsSettingsError::new(TlsSettingsErrorKind::NotConfigured).into())
+    };
+
+    let fetcher = ocsp.map(create_ocsp_fetcher).transpose()?;
+    let pairs = pairs.into_iter()
+        .chain(certificates.into_iter().flatten().map(|x| (x.certificate.as_str(), x.key.as_str(), x.ocsp_response.as_deref())))
+        .map(|(certificate, key, response)| {
+            // A response file takes precedence over fetching from the responder
+            let source = match (response, &fetcher) {
+                (Some(response), _) => Some(OcspSource::File(PathBuf::from(response))),
+                (None, Some(fetcher)) => Some(OcspSource::Fetch(fetcher.clone())),
+                (None, None) => None
+            };
+            FileResolver::new(Path::new(certificate), Path::new(key), source, build)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    match (pairs.len(), acme) {
+        (0, Some(acme)) => Ok(Arc::new(AcmeResolver::new(acme, build)?)),
+        (0, None) => Err(TlsSettingsError::new(TlsSettingsErrorKind::NotConfigured).into()),
+        (1, _) => Ok(Arc::new(pairs.into_iter().next().unwrap())),
+        _ => Ok(Arc::new(MultiResolver::new(pairs)))
+    }
+}
+
+fn create_ocsp_fetcher(settings: &settings::Ocsp) -> Result<Arc<OcspFetcher>, CertificateError> {
+    let responder = settings.responder.as_deref()
+        .map(str::parse)
+        .transpose()
+        .map_err(|e| CertificateError::new(&settings.cache_dir, CertificateErrorKind::Invalid(format!("invalid OCSP responder: {}", e))))?;
+
+    Ok(Arc::new(OcspFetcher::new(Arc::new(HttpOcspClient::new()), &settings.cache_dir, responder)))
+}
+
+fn create_client_verifier(client_auth: Option<&settings::ClientAuth>) -> Result<Arc<dyn ClientCertVerifier>, CertificateError> {
+    match client_auth {
+        Some(client_auth) => create_verifier(client_auth),
+        None => Ok(WebPkiClientVerifier::no_client_auth())
+    }
+}
+
+fn create_config(
+    resolver: Arc<dyn ResolvesServerCert>,
+    client_auth: Option<&settings::ClientAuth>,
+    protocols: &settings::TlsProtocols,
+    alpn: Option<Vec<String>>,
+    ktls: bool,
+    sessions: &Sessions,
+) -> Result<ServerConfig, TlsError> {
+    let verifier = create_client_verifier(client_auth)?;
+    let invalid = |e: String| TlsSettingsError::new(TlsSettingsErrorKind::Protocols(e));
+    let (provider, versions) = create_provider(protocols).map_err(invalid)?;
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&versions)
+        .map_err(|e| invalid(e.to_string()))?
+        .with_client_cert_verifier(verifier)
+        .with_cert_resolver(resolver);
+
+    config.enable_secret_extraction = ktls;
+
+    // Sessions established with one client authentication setup must not be resumed under another
+    let scope = client_auth.map(serde_json::to_string).transpose().map_err(|e| invalid(e.to_string()))?.unwrap_or_default();
+    sessions.apply(&mut config, &scope);
+
+    if let Some(protocols) = alpn {
+        config.alpn_protocols.extend(protocols.iter().map(|x| x.as_str().into()));
+    }
+
+    Ok(config)
+}
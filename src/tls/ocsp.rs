@@ -0,0 +1,725 @@
+use async_trait::async_trait;
+
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+
+use hyper::body::Bytes;
+use hyper::{header, Method, Request, Uri, Version};
+
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use tokio::time::timeout;
+
+use tokio_rustls::rustls::sign::CertifiedKey;
+
+use asn1_rs::{nom, oid, Any, BitString, Class, DerSequence, Enumerated, FromDer, GeneralizedTime, Integer, Oid, Sequence, Tag, ToDer};
+
+use x509_parser::certificate::X509Certificate;
+use x509_parser::error::X509Error;
+use x509_parser::extensions::{GeneralName, ParsedExtension};
+use x509_parser::oid_registry::{OID_HASH_SHA1, OID_NIST_HASH_SHA256, OID_PKIX_ACCESS_DESCRIPTOR_OCSP};
+use x509_parser::parse_x509_certificate;
+use x509_parser::time::ASN1Time;
+use x509_parser::verify::verify_signature;
+use x509_parser::x509::AlgorithmIdentifier;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::Error;
+use crate::http::{Client, Connection};
+
+use super::{CertificateError, CertificateErrorKind};
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_REFRESH: Duration = Duration::from_secs(60 * 60);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const OID_PKIX_OCSP_BASIC: Oid<'static> = oid!(1.3.6.1.5.5.7.48.1.1);
+
+/// Transport for OCSP requests, replaceable to talk to something other than the responders on the internet
+#[async_trait]
+pub trait OcspClient {
+    async fn fetch(&self, responder: &Uri, request: Vec<u8>) -> Result<Vec<u8>, Error>;
+}
+
+pub type SendableOcspClient = Arc<dyn OcspClient + Send + Sync>;
+
+/// Posts requests to the responder as described in RFC 6960 appendix A
+pub struct HttpOcspClient {
+    client: Client
+}
+
+/// Fetches responses for certificates and keeps them in a cache directory
+pub struct OcspFetcher {
+    client: SendableOcspClient,
+    cache_dir: PathBuf,
+    responder: Option<Uri>,
+    timeout: Duration
+}
+
+/// Where the OCSP response of a certificate comes from
+pub enum OcspSource {
+    File(PathBuf),
+    Fetch(Arc<OcspFetcher>)
+}
+
+/// Keeps the OCSP response of one certificate up to date
+pub struct OcspStapler {
+    source: OcspSource,
+    modified: Option<SystemTime>,
+    refresh_at: SystemTime
+}
+
+/// Validity period of a good OCSP response
+struct ResponseValidity {
+    this_update: SystemTime,
+    next_update: Option<SystemTime>
+}
+
+impl HttpOcspClient {
+    pub fn new() -> Self {
+        Self {
+            client: Client::new()
+        }
+    }
+}
+
+impl Default for HttpOcspClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl OcspClient for HttpOcspClient {
+    async fn fetch(&self, responder: &Uri, request: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let mut conn = self.client.get_connection(responder).await?;
+        let builder = match conn.conn {
+            Connection::Http2(_) => Request::builder().uri(responder).version(Version::HTTP_2),
+            _ => Request::builder()
+                .uri(responder.path_and_query().map(|x| x.as_str()).unwrap_or("/"))
+                .header(header::HOST, responder.authority().ok_or("responder URL without host")?.as_str())
+        };
+
+        let request = builder
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, "application/ocsp-request")
+            .body(BoxBody::new(Full::new(Bytes::from(request)).map_err(From::from)))?;
+
+        let response = conn.send_request(request).await?;
+        if !response.status().is_success() {
+            return Err(format!("responder answered with {}", response.status()).into());
+        }
+        Ok(response.collect().await?.to_bytes().to_vec())
+    }
+}
+
+impl OcspFetcher {
+    pub fn new(client: SendableOcspClient, cache_dir: &Path, responder: Option<Uri>) -> Self {
+        Self {
+            client,
+            cache_dir: cache_dir.to_path_buf(),
+            responder,
+            timeout: FETCH_TIMEOUT
+        }
+    }
+
+    /// Previously fetched response which is still usable for the certificate
+    fn cached(&self, key: &CertifiedKey) -> Option<(Vec<u8>, ResponseValidity)> {
+        let response = fs::read(self.cache_path(key)?).ok()?;
+        let validity = check_response(&response, key).ok()?;
+        Some((response, validity))
+    }
+
+    async fn fetch(&self, key: &CertifiedKey) -> Result<(Vec<u8>, ResponseValidity), Error> {
+        let (leaf, issuer) = chain(key)?;
+        let responder = match &self.responder {
+            Some(responder) => responder.clone(),
+            None => responder_url(&leaf).ok_or("certificate doesn't name an OCSP responder")?.parse()?
+        };
+
+        let request = encode_request(&leaf, &issuer)?;
+        let response = timeout(self.timeout, self.client.fetch(&responder, request)).await
+            .map_err(|_| "responder didn't answer in time")??;
+        let validity = check_response(&response, key)?;
+        if let Some(path) = self.cache_path(key) {
+            fs::create_dir_all(&self.cache_dir)?;
+            fs::write(path, &response)?;
+        }
+        Ok((response, validity))
+    }
+
+    fn cache_path(&self, key: &CertifiedKey) -> Option<PathBuf> {
+        let digest = Sha256::digest(key.end_entity_cert().ok()?);
+        Some(self.cache_dir.join(format!("{}.der", digest.iter().map(|x| format!("{:02x}", x)).collect::<String>())))
+    }
+}
+
+impl OcspStapler {
+    pub fn new(source: OcspSource) -> Self {
+        Self {
+            source,
+            modified: None,
+            refresh_at: SystemTime::now()
+        }
+    }
+
+    /// Response to staple right away for a freshly loaded certificate
+    pub fn load(&mut self, key: &CertifiedKey) -> Result<Option<Vec<u8>>, CertificateError> {
+        match &self.source {
+            OcspSource::File(path) => {
+                self.modified = fs::metadata(path).and_then(|x| x.modified()).ok();
+                let response = fs::read(path).map_err(|e| CertificateError::new(path, CertificateErrorKind::Read(e)))?;
+                check_response(&response, key).map_err(|e| CertificateError::new(path, CertificateErrorKind::Invalid(e.to_string())))?;
+                Ok(Some(response))
+            },
+            OcspSource::Fetch(fetcher) => match fetcher.cached(key) {
+                Some((response, validity)) => {
+                    self.refresh_at = validity.refresh_time();
+                    Ok(Some(response))
+                },
+                None => {
+                    self.refresh_at = SystemTime::now();
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    /// New response when the file changed or the previous one is due for a refresh
+    pub async fn update(&mut self, key: &CertifiedKey) -> Option<Vec<u8>> {
+        match &self.source {
+            OcspSource::File(path) => {
+                let modified = fs::metadata(path).and_then(|x| x.modified()).ok();
+                if modified == self.modified {
+                    return None;
+                }
+
+                let path = path.clone();
+                match self.load(key) {
+                    Ok(response) => {
+                        println!("Reloaded OCSP response {}", path.display());
+                        response
+                    },
+                    Err(e) => {
+                        println!("Keeping the previous OCSP response: {}", e);
+                        None
+                    }
+                }
+            },
+            OcspSource::Fetch(fetcher) => {
+                if SystemTime::now() < self.refresh_at {
+                    return None;
+                }
+
+                match fetcher.fetch(key).await {
+                    Ok((response, validity)) => {
+                        self.refresh_at = validity.refresh_time();
+                        Some(response)
+                    },
+                    Err(e) => {
+                        println!("Failed to fetch OCSP response for {}: {}", subject(key), e);
+                        self.refresh_at = SystemTime::now() + RETRY_INTERVAL;
+                        None
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl ResponseValidity {
+    /// Refresh halfway through the validity period, so a failing responder leaves plenty of time for retries
+    fn refresh_time(&self) -> SystemTime {
+        match self.next_update {
+            Some(next_update) => {
+                let lifetime = next_update.duration_since(self.this_update).unwrap_or_default();
+                self.this_update + lifetime / 2
+            },
+            None => SystemTime::now() + DEFAULT_REFRESH
+        }
+    }
+}
+
+fn responder_url(leaf: &x509_parser::certificate::X509Certificate<'_>) -> Option<String> {
+    leaf.iter_extensions().find_map(|extension| match extension.parsed_extension() {
+        ParsedExtension::AuthorityInfoAccess(aia) => aia.accessdescs.iter()
+            .filter(|x| x.access_method == OID_PKIX_ACCESS_DESCRIPTOR_OCSP)
+            .find_map(|x| match x.access_location {
+                GeneralName::URI(uri) if uri.starts_with("http") => Some(uri.to_string()),
+                _ => None
+            }),
+        _ => None
+    })
+}
+
+fn subject(key: &CertifiedKey) -> String {
+    key.end_entity_cert().ok()
+        .and_then(|x| parse_x509_certificate(x).ok().map(|(_, x)| x.subject().to_string()))
+        .unwrap_or_default()
+}
+
+/// Certificate and its issuer, which has to follow it in the chain
+fn chain(key: &CertifiedKey) -> Result<(X509Certificate<'_>, X509Certificate<'_>), Error> {
+    let (_, leaf) = parse_x509_certificate(key.end_entity_cert()?)?;
+    let (_, issuer) = parse_x509_certificate(key.cert.get(1).ok_or("issuer certificate missing from the chain")?)?;
+    Ok((leaf, issuer))
+}
+
+/// Check that the response is signed on behalf of the issuer, says the certificate is good and hasn't expired
+fn check_response(response: &[u8], key: &CertifiedKey) -> Result<ResponseValidity, Error> {
+    let (leaf, issuer) = chain(key)?;
+    let (_, response) = OcspResponse::from_der(response)?;
+    let basic = response.basic()?;
+    let data = basic.verify(&issuer)?;
+
+    let single = data.responses.iter()
+        .find(|x| x.cert_id.covers(&leaf, &issuer))
+        .ok_or("OCSP response doesn't cover the certificate")?;
+
+    // CertStatus ::= CHOICE { good [0] IMPLICIT NULL, revoked [1] IMPLICIT RevokedInfo, unknown [2] IMPLICIT UnknownInfo }
+    match (single.cert_status.class(), single.cert_status.tag()) {
+        (Class::ContextSpecific, Tag(0)) => (),
+        (Class::ContextSpecific, Tag(1)) => return Err("certificate has been revoked".into()),
+        _ => return Err("responder doesn't know the certificate".into())
+    }
+
+    let this_update = ASN1Time::from(single.this_update.utc_datetime()?);
+    let next_update = single.next_update.as_ref().map(|x| x.utc_datetime().map(ASN1Time::from)).transpose()?;
+    let now = ASN1Time::now();
+    if this_update > now {
+        return Err("OCSP response isn't valid yet".into());
+    }
+    if next_update.is_some_and(|x| x < now) {
+        return Err("OCSP response has expired".into());
+    }
+    Ok(ResponseValidity {
+        this_update: system_time(this_update),
+        next_update: next_update.map(system_time)
+    })
+}
+
+fn system_time(time: ASN1Time) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(u64::try_from(time.timestamp()).unwrap_or_default())
+}
+
+/// OCSPResponse (RFC 6960 section 4.2.1)
+#[derive(DerSequence)]
+struct OcspResponse<'a> {
+    response_status: Enumerated,
+    #[tag_explicit(0)]
+    #[optional]
+    response_bytes: Option<ResponseBytes<'a>>
+}
+
+#[derive(DerSequence)]
+struct ResponseBytes<'a> {
+    response_type: Oid<'a>,
+    response: &'a [u8]
+}
+
+/// BasicOCSPResponse, tbsResponseData is kept whole because the signature covers its encoding
+#[derive(DerSequence)]
+#[error(X509Error)]
+struct BasicOcspResponse<'a> {
+    tbs_response_data: Any<'a>,
+    signature_algorithm: AlgorithmIdentifier<'a>,
+    signature: BitString<'a>,
+    #[tag_explicit(0)]
+    #[optional]
+    certs: Option<Vec<Any<'a>>>
+}
+
+/// Trailing responseExtensions are left out, they're never looked at
+#[derive(DerSequence)]
+struct ResponseData<'a> {
+    #[tag_explicit(0)]
+    #[optional]
+    _version: Option<u32>,
+    responder_id: Any<'a>,
+    _produced_at: GeneralizedTime,
+    responses: Vec<SingleResponse<'a>>
+}
+
+/// Status of one certificate, identified by its CertID
+#[derive(DerSequence)]
+struct SingleResponse<'a> {
+    cert_id: CertId<'a>,
+    cert_status: Any<'a>,
+    this_update: GeneralizedTime,
+    #[tag_explicit(0)]
+    #[optional]
+    next_update: Option<GeneralizedTime>
+}
+
+#[derive(DerSequence)]
+struct CertId<'a> {
+    hash_algorithm: HashAlgorithm<'a>,
+    issuer_name_hash: &'a [u8],
+    issuer_key_hash: &'a [u8],
+    serial_number: Integer<'a>
+}
+
+#[derive(DerSequence)]
+struct HashAlgorithm<'a> {
+    algorithm: Oid<'a>,
+    #[optional]
+    _parameters: Option<Any<'a>>
+}
+
+impl<'a> OcspResponse<'a> {
+    /// The BasicOCSPResponse of a successful response
+    fn basic(&self) -> Result<BasicOcspResponse<'a>, Error> {
+        if self.response_status.0 != 0 {
+            return Err(format!("responder returned status {}", self.response_status.0).into());
+        }
+
+        let bytes = self.response_bytes.as_ref().ok_or("OCSP response without content")?;
+        if bytes.response_type != OID_PKIX_OCSP_BASIC {
+            return Err(format!("unsupported OCSP response type {}", bytes.response_type).into());
+        }
+        Ok(BasicOcspResponse::from_der(bytes.response)?.1)
+    }
+}
+
+impl BasicOcspResponse<'_> {
+    /// The signer is the issuer itself or a responder it delegated to (RFC 6960 section 4.2.2.2)
+    fn verify(&self, issuer: &X509Certificate<'_>) -> Result<ResponseData<'_>, Error> {
+        let data = ResponseData::try_from(self.tbs_response_data.clone())?;
+        let certs = self.certs.iter().flatten().map(|x| x.to_der_vec()).collect::<Result<Vec<_>, _>>()?;
+        let certs = certs.iter().map(|x| Ok(X509Certificate::from_der(x)?.1)).collect::<Result<Vec<_>, Error>>()?;
+        let signer = std::iter::once(issuer)
+            .chain(&certs)
+            .find(|x| data.names(x))
+            .ok_or("OCSP response signer is unknown")?;
+
+        if !ptr::eq(signer, issuer) {
+            let ocsp_signing = signer.extended_key_usage().ok().flatten().is_some_and(|x| x.value.ocsp_signing);
+            if signer.issuer() != issuer.subject() || !ocsp_signing || !signer.validity().is_valid() {
+                return Err("OCSP responder isn't authorized by the issuer".into());
+            }
+            signer.verify_signature(Some(issuer.public_key()))
+                .map_err(|_| "OCSP responder certificate isn't signed by the issuer")?;
+        }
+
+        let tbs_response_data = self.tbs_response_data.to_der_vec()?;
+        verify_signature(signer.public_key(), &self.signature_algorithm, &self.signature, &tbs_response_data)
+            .map_err(|_| "OCSP response signature is invalid")?;
+        Ok(data)
+    }
+}
+
+impl ResponseData<'_> {
+    fn names(&self, certificate: &X509Certificate<'_>) -> bool {
+        match (self.responder_id.class(), self.responder_id.tag()) {
+            // byName [1] EXPLICIT Name
+            (Class::ContextSpecific, Tag(1)) => self.responder_id.data == certificate.subject().as_raw(),
+            // byKey [2] EXPLICIT KeyHash, the SHA-1 hash of the public key
+            (Class::ContextSpecific, Tag(2)) => <&[u8]>::from_der(self.responder_id.data)
+                .is_ok_and(|(_, x)| x == &Sha1::digest(&certificate.public_key().subject_public_key.data)[..]),
+            _ => false
+        }
+    }
+}
+
+impl CertId<'_> {
+    /// Whether the CertID identifies the certificate, with any of the hashes responders use
+    fn covers(&self, leaf: &X509Certificate<'_>, issuer: &X509Certificate<'_>) -> bool {
+        let name = leaf.issuer().as_raw();
+        let key = &issuer.public_key().subject_public_key.data;
+        let (name_hash, key_hash) = match &self.hash_algorithm.algorithm {
+            x if *x == OID_HASH_SHA1 => (Sha1::digest(name).to_vec(), Sha1::digest(key).to_vec()),
+            x if *x == OID_NIST_HASH_SHA256 => (Sha256::digest(name).to_vec(), Sha256::digest(key).to_vec()),
+            _ => return false
+        };
+
+        self.serial_number.as_ref() == leaf.raw_serial() && self.issuer_name_hash == name_hash && self.issuer_key_hash == key_hash
+    }
+}
+
+/// OCSPRequest for the certificate, identified by SHA-1 hashes which every responder has to support
+fn encode_request(leaf: &X509Certificate<'_>, issuer: &X509Certificate<'_>) -> Result<Vec<u8>, Error> {
+    let hash_algorithm = [OID_HASH_SHA1.to_der_vec()?, ().to_der_vec()?].concat();
+    let cert_id = [
+        Sequence::new(hash_algorithm.into()).to_der_vec()?,
+        (&Sha1::digest(leaf.issuer().as_raw())[..]).to_der_vec()?,
+        (&Sha1::digest(&issuer.public_key().subject_public_key.data)[..]).to_der_vec()?,
+        Integer::new(leaf.raw_serial()).to_der_vec()?
+    ].concat();
+
+    // Request, requestList, TBSRequest and OCSPRequest each hold just the one element
+    let mut request = Sequence::new(cert_id.into()).to_der_vec()?;
+    for _ in 0..4 {
+        request = Sequence::new(request.into()).to_der_vec()?;
+    }
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair, SigningKey};
+
+    use tokio_rustls::rustls::pki_types::PrivateKeyDer;
+
+    use std::sync::Mutex;
+
+    use super::super::provider;
+    use super::*;
+
+    const SHA1_ALGORITHM: &[u8] = &[0x30, 0x09, 0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a, 0x05, 0x00];
+    // ecdsa-with-SHA256, the signature of rcgen's default keys
+    const ECDSA_SHA256: &[u8] = &[0x30, 0x0a, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+    const OCSP_BASIC: &[u8] = &[0x06, 0x09, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+    const GOOD: &[u8] = &[0x80, 0x00];
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    const TAG_INTEGER: u8 = 0x02;
+    const TAG_OCTET_STRING: u8 = 0x04;
+    const TAG_SEQUENCE: u8 = 0x30;
+
+    type Signer = CertifiedIssuer<'static, KeyPair>;
+
+    fn authority(name: &str) -> Signer {
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap()
+    }
+
+    fn leaf(ca: &Signer) -> CertifiedKey {
+        let key = KeyPair::generate().unwrap();
+        let certificate = CertificateParams::new(vec!["localhost".to_string()]).unwrap().signed_by(&key, ca).unwrap();
+        let key = PrivateKeyDer::try_from(key.serialize_der()).unwrap();
+        CertifiedKey::from_der(vec![certificate.der().clone(), ca.der().clone()], key, &provider()).unwrap()
+    }
+
+    fn responder(ca: &Signer, usages: Vec<ExtendedKeyUsagePurpose>) -> Signer {
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, "OCSP responder");
+        params.extended_key_usages = usages;
+        CertifiedIssuer::signed_by(params, KeyPair::generate().unwrap(), ca).unwrap()
+    }
+
+    fn encode(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![tag];
+        let length = content.len().to_be_bytes();
+        let length = &length[length.iter().position(|x| *x != 0).unwrap_or(length.len() - 1)..];
+        match content.len() {
+            0..0x80 => encoded.push(content.len() as u8),
+            _ => {
+                encoded.push(0x80 | length.len() as u8);
+                encoded.extend_from_slice(length);
+            }
+        }
+        encoded.extend_from_slice(content);
+        encoded
+    }
+
+    fn time(time: SystemTime) -> Vec<u8> {
+        encode(0x18, chrono::DateTime::<chrono::Utc>::from(time).format("%Y%m%d%H%M%SZ").to_string().as_bytes())
+    }
+
+    /// Response about the certificate, built the way a responder would
+    struct Response<'a> {
+        signer: &'a Signer,
+        certs: Vec<&'a Signer>,
+        serial: Option<Vec<u8>>,
+        status: &'a [u8],
+        next_update: SystemTime
+    }
+
+    impl<'a> Response<'a> {
+        fn new(signer: &'a Signer) -> Self {
+            Self {
+                signer,
+                certs: Vec::new(),
+                serial: None,
+                status: GOOD,
+                next_update: SystemTime::now() + DAY
+            }
+        }
+
+        fn encode(&self, key: &CertifiedKey) -> Vec<u8> {
+            let (leaf, issuer) = chain(key).unwrap();
+            let cert_id = [
+                SHA1_ALGORITHM.to_vec(),
+                encode(TAG_OCTET_STRING, &Sha1::digest(leaf.issuer().as_raw())),
+                encode(TAG_OCTET_STRING, &Sha1::digest(&issuer.public_key().subject_public_key.data)),
+                encode(TAG_INTEGER, self.serial.as_deref().unwrap_or(leaf.raw_serial()))
+            ].concat();
+            let single = [
+                encode(TAG_SEQUENCE, &cert_id),
+                self.status.to_vec(),
+                time(SystemTime::now() - DAY / 24),
+                encode(0xa0, &time(self.next_update))
+            ].concat();
+
+            let (_, signer) = parse_x509_certificate(self.signer.der()).unwrap();
+            let responder_id = encode(0xa2, &encode(TAG_OCTET_STRING, &Sha1::digest(&signer.public_key().subject_public_key.data)));
+            let data = encode(TAG_SEQUENCE, &[
+                responder_id,
+                time(SystemTime::now()),
+                encode(TAG_SEQUENCE, &encode(TAG_SEQUENCE, &single))
+            ].concat());
+
+            let signature = self.signer.key().sign(&data).unwrap();
+            let mut basic = [data, ECDSA_SHA256.to_vec(), encode(0x03, &[&[0], signature.as_slice()].concat())].concat();
+            if !self.certs.is_empty() {
+                let certs = self.certs.iter().map(|x| x.der().to_vec()).collect::<Vec<_>>().concat();
+                basic.extend(encode(0xa0, &encode(TAG_SEQUENCE, &certs)));
+            }
+
+            let bytes = encode(TAG_SEQUENCE, &[OCSP_BASIC.to_vec(), encode(TAG_OCTET_STRING, &encode(TAG_SEQUENCE, &basic))].concat());
+            encode(TAG_SEQUENCE, &[encode(0x0a, &[0]), encode(0xa0, &bytes)].concat())
+        }
+    }
+
+    /// Local stand-in for a responder, answering every request with the same response
+    struct StandIn {
+        response: Vec<u8>,
+        requests: Mutex<Vec<Vec<u8>>>
+    }
+
+    #[async_trait]
+    impl OcspClient for StandIn {
+        async fn fetch(&self, _responder: &Uri, request: Vec<u8>) -> Result<Vec<u8>, Error> {
+            self.requests.lock().unwrap().push(request);
+            Ok(self.response.clone())
+        }
+    }
+
+    struct Unresponsive;
+
+    #[async_trait]
+    impl OcspClient for Unresponsive {
+        async fn fetch(&self, _responder: &Uri, _request: Vec<u8>) -> Result<Vec<u8>, Error> {
+            std::future::pending().await
+        }
+    }
+
+    fn fetcher(client: SendableOcspClient, name: &str) -> OcspFetcher {
+        let cache_dir = std::env::temp_dir().join(format!("rproxy-ocsp-{}-{}", name, std::process::id()));
+        OcspFetcher::new(client, &cache_dir, Some("http://127.0.0.1/ocsp".parse().unwrap()))
+    }
+
+    #[test]
+    fn signed_by_issuer() {
+        let ca = authority("CA");
+        let key = leaf(&ca);
+        assert!(check_response(&Response::new(&ca).encode(&key), &key).is_ok());
+    }
+
+    #[test]
+    fn signed_by_delegated_responder() {
+        let ca = authority("CA");
+        let key = leaf(&ca);
+        let responder = responder(&ca, vec![ExtendedKeyUsagePurpose::OcspSigning]);
+        let response = Response { certs: vec![&responder], ..Response::new(&responder) };
+        assert!(check_response(&response.encode(&key), &key).is_ok());
+    }
+
+    #[test]
+    fn responder_without_ocsp_signing() {
+        let ca = authority("CA");
+        let key = leaf(&ca);
+        let responder = responder(&ca, vec![ExtendedKeyUsagePurpose::ServerAuth]);
+        let response = Response { certs: vec![&responder], ..Response::new(&responder) };
+        assert!(check_response(&response.encode(&key), &key).is_err());
+    }
+
+    #[test]
+    fn responder_of_other_issuer() {
+        let ca = authority("CA");
+        let key = leaf(&ca);
+        let other = authority("Other CA");
+        let responder = responder(&other, vec![ExtendedKeyUsagePurpose::OcspSigning]);
+        let response = Response { certs: vec![&responder], ..Response::new(&responder) };
+        assert!(check_response(&response.encode(&key), &key).is_err());
+
+        // Without the certificate the signer can't be found at all
+        assert!(check_response(&Response::new(&other).encode(&key), &key).is_err());
+    }
+
+    #[test]
+    fn tampered_signature() {
+        let ca = authority("CA");
+        let key = leaf(&ca);
+        let mut response = Response::new(&ca).encode(&key);
+        let last = response.len() - 1;
+        response[last] ^= 1;
+        assert!(check_response(&response, &key).is_err());
+    }
+
+    #[test]
+    fn other_certificate() {
+        let ca = authority("CA");
+        let key = leaf(&ca);
+        let response = Response { serial: Some(vec![0x01]), ..Response::new(&ca) };
+        assert!(check_response(&response.encode(&key), &key).is_err());
+
+        // Same serial, but issued by a different CA
+        let other = authority("Other CA");
+        let mut other_key = leaf(&other);
+        let (other_leaf, _) = chain(&other_key).unwrap();
+        let serial = other_leaf.raw_serial().to_vec();
+        other_key.cert[1] = ca.der().clone();
+        let response = Response { serial: Some(serial), ..Response::new(&ca) };
+        assert!(check_response(&response.encode(&other_key), &key).is_err());
+    }
+
+    #[test]
+    fn revoked_and_expired() {
+        let ca = authority("CA");
+        let key = leaf(&ca);
+        let revoked = [0xa1, 0x11].into_iter().chain(time(SystemTime::now())).collect::<Vec<_>>();
+        let response = Response { status: &revoked, ..Response::new(&ca) };
+        assert!(check_response(&response.encode(&key), &key).is_err());
+
+        let response = Response { next_update: SystemTime::now() - DAY / 2, ..Response::new(&ca) };
+        assert!(check_response(&response.encode(&key), &key).is_err());
+    }
+
+    #[tokio::test]
+    async fn fetch_from_stand_in() {
+        let ca = authority("CA");
+        let key = leaf(&ca);
+        let client = Arc::new(StandIn {
+            response: Response::new(&ca).encode(&key),
+            requests: Mutex::default()
+        });
+
+        let fetcher = Arc::new(fetcher(client.clone(), "fetch"));
+        let mut stapler = OcspStapler::new(OcspSource::Fetch(fetcher.clone()));
+        assert_eq!(stapler.load(&key).unwrap(), None);
+        assert_eq!(stapler.update(&key).await, Some(client.response.clone()));
+
+        let (leaf, issuer) = chain(&key).unwrap();
+        let cert_id = [
+            SHA1_ALGORITHM.to_vec(),
+            encode(TAG_OCTET_STRING, &Sha1::digest(leaf.issuer().as_raw())),
+            encode(TAG_OCTET_STRING, &Sha1::digest(&issuer.public_key().subject_public_key.data)),
+            encode(TAG_INTEGER, leaf.raw_serial())
+        ].concat();
+        let request = (0..5).fold(cert_id, |x, _| encode(TAG_SEQUENCE, &x));
+        assert_eq!(encode_request(&leaf, &issuer).unwrap(), request);
+        assert_eq!(*client.requests.lock().unwrap(), vec![request]);
+
+        // The cached response is stapled right away after a restart
+        let mut stapler = OcspStapler::new(OcspSource::Fetch(fetcher.clone()));
+        assert_eq!(stapler.load(&key).unwrap(), Some(client.response.clone()));
+        assert_eq!(stapler.update(&key).await, None);
+        fs::remove_dir_all(&fetcher.cache_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fetch_times_out() {
+        let ca = authority("CA");
+        let key = leaf(&ca);
+        let mut fetcher = fetcher(Arc::new(Unresponsive), "timeout");
+        fetcher.timeout = Duration::from_millis(50);
+        assert!(fetcher.fetch(&key).await.is_err());
+    }
+}
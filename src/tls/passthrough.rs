@@ -0,0 +1,61 @@
+use async_trait::async_trait;
+
+use wildmatch::WildMatch;
+
+use std::error::Error;
+
+use crate::handler::{Context, Handler, SendableHandler};
+use crate::io::ProxyStream;
+use crate::settings;
+
+use super::client_hello::read_client_hello;
+use super::handshake::HandshakeLimits;
+
+pub struct PassthroughRoute {
+    hostname: WildMatch,
+    handler: SendableHandler
+}
+
+/// Routes TLS connections by the SNI of the ClientHello without terminating them
+pub struct PassthroughHandler {
+    sni: Vec<PassthroughRoute>,
+    handler: Option<SendableHandler>,
+    limits: HandshakeLimits
+}
+
+impl PassthroughRoute {
+    pub fn new(hostname: &str, handler: SendableHandler) -> Self {
+        Self {
+            hostname: WildMatch::new(hostname),
+            handler
+        }
+    }
+}
+
+impl PassthroughHandler {
+    pub fn new(sni: Vec<PassthroughRoute>, handler: Option<SendableHandler>, limits: &settings::HandshakeLimits) -> Self {
+        Self {
+            sni,
+            handler,
+            limits: HandshakeLimits::new(limits)
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for PassthroughHandler {
+    async fn handle(&self, mut stream: ProxyStream, mut ctx: Context) -> Result<(), Box<dyn Error>> {
+        let mut prefix = Vec::new();
+        let hello = self.limits.passthrough(read_client_hello(&mut stream, &mut prefix, self.limits.max_client_hello)).await?;
+
+        let handler = self.sni.iter()
+            .find(|x| hello.server_name.as_deref().is_some_and(|name| x.hostname.matches(name)))
+            .map(|x| &x.handler)
+            .or(self.handler.as_ref())
+            .ok_or_else(|| format!("No passthrough handler for server name {}", hello.server_name.as_deref().unwrap_or("-")))?;
+
+        // The handler receives the connection from the start, including the ClientHello read here
+        ctx.server_name = hello.server_name;
+        handler.handle(ProxyStream::new_prefixed(prefix, stream), ctx).await
+    }
+}
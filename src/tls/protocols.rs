@@ -0,0 +1,119 @@
+use tokio_rustls::rustls::crypto::{aws_lc_rs, CryptoProvider};
+use tokio_rustls::rustls::{version, CipherSuite, NamedGroup, SupportedProtocolVersion};
+
+use std::sync::Arc;
+
+use crate::settings::{TlsProtocols, TlsVersion};
+
+const VERSIONS: &[(TlsVersion, &SupportedProtocolVersion)] = &[
+    (TlsVersion::V1_2, &version::TLS12),
+    (TlsVersion::V1_3, &version::TLS13)
+];
+
+/// Cipher suites by their name in the IANA TLS parameters registry, rustls prefixes the TLS 1.3 ones with TLS13_
+const CIPHER_SUITES: &[(&str, CipherSuite)] = &[
+    ("TLS_AES_256_GCM_SHA384", CipherSuite::TLS13_AES_256_GCM_SHA384),
+    ("TLS_AES_128_GCM_SHA256", CipherSuite::TLS13_AES_128_GCM_SHA256),
+    ("TLS_CHACHA20_POLY1305_SHA256", CipherSuite::TLS13_CHACHA20_POLY1305_SHA256),
+    ("TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384", CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384),
+    ("TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256", CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256),
+    ("TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256", CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256),
+    ("TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384", CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384),
+    ("TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256", CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256),
+    ("TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256", CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256)
+];
+
+/// Key exchange groups by their name in the IANA TLS parameters registry
+const KX_GROUPS: &[(&str, NamedGroup)] = &[
+    ("X25519", NamedGroup::X25519),
+    ("secp256r1", NamedGroup::secp256r1),
+    ("secp384r1", NamedGroup::secp384r1),
+    ("X25519MLKEM768", NamedGroup::X25519MLKEM768),
+    ("SecP256r1MLKEM768", NamedGroup::secp256r1MLKEM768),
+    ("MLKEM768", NamedGroup::MLKEM768)
+];
+
+/// Crypto provider and protocol versions restricted to the configured subset, unknown names are rejected.
+/// Cipher suites are also accepted under their rustls names for configurations written against them
+pub fn create_provider(settings: &TlsProtocols) -> Result<(Arc<CryptoProvider>, Vec<&'static SupportedProtocolVersion>), String> {
+    let mut provider = aws_lc_rs::default_provider();
+
+    if let Some(names) = &settings.cipher_suites {
+        provider.cipher_suites = names.iter().map(|name| {
+            let suite = CIPHER_SUITES.iter().find(|(x, _)| x.eq_ignore_ascii_case(name)).map(|(_, x)| *x);
+            aws_lc_rs::ALL_CIPHER_SUITES.iter()
+                .find(|x| suite == Some(x.suite()) || x.suite().as_str().is_some_and(|x| x.eq_ignore_ascii_case(name)))
+                .copied()
+                .ok_or(format!("unknown cipher suite {}", name))
+        }).collect::<Result<_, _>>()?;
+    }
+
+    if let Some(names) = &settings.kx_groups {
+        provider.kx_groups = names.iter().map(|name| {
+            let group = KX_GROUPS.iter().find(|(x, _)| x.eq_ignore_ascii_case(name)).map(|(_, x)| *x);
+            aws_lc_rs::ALL_KX_GROUPS.iter()
+                .find(|x| group.is_some_and(|group| x.name() == group))
+                .copied()
+                .ok_or(format!("unknown key exchange group {}", name))
+        }).collect::<Result<_, _>>()?;
+    }
+
+    let versions = VERSIONS.iter()
+        .filter(|(x, _)| settings.min_version.is_none_or(|min| *x >= min) && settings.max_version.is_none_or(|max| *x <= max))
+        .map(|(_, x)| *x)
+        .collect::<Vec<_>>();
+
+    if versions.is_empty() {
+        return Err("no TLS version between min_version and max_version".to_string());
+    }
+    if !provider.cipher_suites.iter().any(|x| versions.iter().any(|v| x.version() == *v)) {
+        return Err("none of the cipher suites can be used with the enabled TLS versions".to_string());
+    }
+
+    Ok((Arc::new(provider), versions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn protocols(min: Option<TlsVersion>, max: Option<TlsVersion>, cipher_suites: &[&str], kx_groups: &[&str]) -> TlsProtocols {
+        let names = |x: &[&str]| (!x.is_empty()).then(|| x.iter().map(|x| x.to_string()).collect());
+        TlsProtocols {
+            min_version: min,
+            max_version: max,
+            cipher_suites: names(cipher_suites),
+            kx_groups: names(kx_groups)
+        }
+    }
+
+    #[test]
+    fn iana_and_rustls_names() {
+        let (provider, versions) = create_provider(&protocols(None, None, &["TLS_AES_256_GCM_SHA384", "tls13_chacha20_poly1305_sha256", "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"], &["x25519"])).unwrap();
+        let suites = provider.cipher_suites.iter().map(|x| x.suite()).collect::<Vec<_>>();
+        assert_eq!(suites, [CipherSuite::TLS13_AES_256_GCM_SHA384, CipherSuite::TLS13_CHACHA20_POLY1305_SHA256, CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256]);
+        assert_eq!(provider.kx_groups.iter().map(|x| x.name()).collect::<Vec<_>>(), [NamedGroup::X25519]);
+        assert_eq!(versions.len(), 2);
+    }
+
+    #[test]
+    fn unknown_names() {
+        assert!(create_provider(&protocols(None, None, &["TLS_RSA_WITH_RC4_128_MD5"], &[])).is_err_and(|e| e.contains("TLS_RSA_WITH_RC4_128_MD5")));
+        assert!(create_provider(&protocols(None, None, &[], &["ffdhe2048"])).is_err_and(|e| e.contains("ffdhe2048")));
+    }
+
+    #[test]
+    fn versions() {
+        let (_, versions) = create_provider(&protocols(Some(TlsVersion::V1_3), None, &[], &[])).unwrap();
+        assert!(versions == [&version::TLS13]);
+
+        assert!(create_provider(&protocols(Some(TlsVersion::V1_3), Some(TlsVersion::V1_2), &[], &[])).is_err());
+    }
+
+    #[test]
+    fn suites_outside_versions() {
+        assert!(create_provider(&protocols(Some(TlsVersion::V1_3), None, &["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"], &[])).is_err());
+        assert!(create_provider(&protocols(None, Some(TlsVersion::V1_2), &["TLS_AES_128_GCM_SHA256"], &[])).is_err());
+        assert!(create_provider(&protocols(None, Some(TlsVersion::V1_2), &["TLS_AES_128_GCM_SHA256", "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"], &[])).is_ok());
+    }
+}
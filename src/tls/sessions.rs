@@ -0,0 +1,232 @@
+use aws_lc_rs::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use aws_lc_rs::hkdf::{Salt, HKDF_SHA256};
+use aws_lc_rs::rand::{SecureRandom, SystemRandom};
+
+use sha2::{Digest, Sha256};
+
+use tokio_rustls::rustls::server::{NoServerSessionStorage, ProducesTickets, ServerSessionMemoryCache, StoresServerSessions};
+use tokio_rustls::rustls::ServerConfig;
+
+use std::fs;
+use std::sync::{Arc, LazyLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::Error;
+use crate::settings;
+
+const DEFAULT_CACHE_SIZE: usize = 256;
+const DEFAULT_ROTATION: u64 = 12 * 60 * 60;
+const MIN_SECRET_LENGTH: usize = 32;
+const EPOCH_LENGTH: usize = 8;
+
+// Kept for the lifetime of the process, so reloading the configuration doesn't invalidate tickets
+static RANDOM_SECRET: LazyLock<Arc<[u8]>> = LazyLock::new(|| {
+    let mut secret = [0; MIN_SECRET_LENGTH];
+    SystemRandom::new().fill(&mut secret).expect("random ticket secret");
+    Arc::new(secret)
+});
+
+/// Session cache and ticket keys shared by all TLS handlers
+pub struct Sessions {
+    cache_size: usize,
+    storage: Arc<dyn StoresServerSessions>,
+    tickets: Option<TicketKeys>
+}
+
+#[derive(Debug, Clone)]
+struct TicketKeys {
+    secret: Arc<[u8]>,
+    rotation: u64
+}
+
+/// Session cache entries of one handler, resuming a session under different client authentication isn't possible
+#[derive(Debug)]
+struct ScopedStorage {
+    scope: Vec<u8>,
+    storage: Arc<dyn StoresServerSessions>
+}
+
+/// Encrypts tickets with keys derived from a shared secret for each rotation period,
+/// instances using the same secret rotate in lockstep without coordination
+#[derive(Debug)]
+struct Ticketer {
+    keys: TicketKeys,
+    scope: Vec<u8>,
+    random: SystemRandom
+}
+
+impl Sessions {
+    /// Parse the session settings, the cache of `previous` is kept when its size is unchanged so reloads don't invalidate sessions
+    pub fn new(settings: Option<&settings::TlsSessions>, previous: Option<&Sessions>) -> Result<Self, Error> {
+        let cache_size = settings.and_then(|x| x.cache_size).unwrap_or(DEFAULT_CACHE_SIZE);
+        let tickets = match settings {
+            Some(settings) if settings.tickets.unwrap_or(true) => {
+                let secret: Arc<[u8]> = match &settings.ticket_key {
+                    Some(path) => {
+                        let secret = fs::read(path).map_err(|e| format!("Can't read ticket key {}: {}", path.display(), e))?;
+                        if secret.len() < MIN_SECRET_LENGTH {
+                            return Err(format!("Ticket key {} must be at least {} bytes", path.display(), MIN_SECRET_LENGTH).into());
+                        }
+                        secret.into()
+                    },
+                    None => RANDOM_SECRET.clone()
+                };
+
+                let rotation = settings.ticket_rotation.unwrap_or(DEFAULT_ROTATION);
+                if rotation == 0 {
+                    return Err("Ticket rotation must be at least one second".into());
+                }
+                Some(TicketKeys { secret, rotation })
+            },
+            _ => None
+        };
+
+        let storage: Arc<dyn StoresServerSessions> = match (cache_size, previous) {
+            (_, Some(previous)) if previous.cache_size == cache_size => previous.storage.clone(),
+            (0, _) => Arc::new(NoServerSessionStorage {}),
+            (size, _) => ServerSessionMemoryCache::new(size)
+        };
+
+        Ok(Self {
+            cache_size,
+            storage,
+            tickets
+        })
+    }
+
+    /// Use the shared session cache and tickets in a handler config, `scope` separates handlers with different client authentication
+    pub fn apply(&self, config: &mut ServerConfig, scope: &str) {
+        let scope = Sha256::digest(scope.as_bytes()).to_vec();
+
+        config.session_storage = Arc::new(ScopedStorage {
+            scope: scope.clone(),
+            storage: self.storage.clone()
+        });
+
+        if let Some(keys) = &self.tickets {
+            config.ticketer = Arc::new(Ticketer {
+                keys: keys.clone(),
+                scope,
+                random: SystemRandom::new()
+            });
+        }
+    }
+}
+
+impl ScopedStorage {
+    fn key(&self, key: &[u8]) -> Vec<u8> {
+        [&self.scope, key].concat()
+    }
+}
+
+impl StoresServerSessions for ScopedStorage {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.storage.put(self.key(&key), value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.storage.get(&self.key(key))
+    }
+
+    fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.storage.take(&self.key(key))
+    }
+
+    fn can_cache(&self) -> bool {
+        self.storage.can_cache()
+    }
+}
+
+impl Ticketer {
+    fn epoch(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / self.keys.rotation
+    }
+
+    fn key(&self, epoch: u64) -> Option<LessSafeKey> {
+        let info = [b"rproxy ticket key".as_slice(), &epoch.to_be_bytes(), &self.scope];
+        let prk = Salt::new(HKDF_SHA256, &[]).extract(&self.keys.secret);
+        let key: UnboundKey = prk.expand(&info, &AES_256_GCM).ok()?.into();
+        Some(LessSafeKey::new(key))
+    }
+}
+
+impl ProducesTickets for Ticketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        u32::try_from(self.keys.rotation).unwrap_or(u32::MAX)
+    }
+
+    /// The ticket is the rotation period, a random nonce and the sealed session
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let epoch = self.epoch().to_be_bytes();
+        let mut nonce = [0; NONCE_LEN];
+        self.random.fill(&mut nonce).ok()?;
+
+        let mut sealed = plain.to_vec();
+        self.key(self.epoch())?
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(epoch), &mut sealed)
+            .ok()?;
+
+        Some([&epoch[..], &nonce, &sealed].concat())
+    }
+
+    /// Tickets of the current and the previous rotation period are accepted
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        let (epoch, rest) = cipher.split_at_checked(EPOCH_LENGTH)?;
+        let (nonce, sealed) = rest.split_at_checked(NONCE_LEN)?;
+        let ticket_epoch = u64::from_be_bytes(epoch.try_into().ok()?);
+        let current = self.epoch();
+        if ticket_epoch != current && ticket_epoch.checked_add(1) != Some(current) {
+            return None;
+        }
+
+        let mut plain = sealed.to_vec();
+        let length = self.key(ticket_epoch)?
+            .open_in_place(Nonce::try_assume_unique_for_key(nonce).ok()?, Aad::from(epoch), &mut plain)
+            .ok()?
+            .len();
+        plain.truncate(length);
+        Some(plain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(cache_size: usize, rotation: Option<u64>) -> settings::TlsSessions {
+        settings::TlsSessions {
+            cache_size: Some(cache_size),
+            tickets: None,
+            ticket_key: None,
+            ticket_rotation: rotation
+        }
+    }
+
+    #[test]
+    fn cache_kept_across_reloads() {
+        let previous = Sessions::new(Some(&settings(16, None)), None).unwrap();
+        previous.storage.put(b"id".to_vec(), b"session".to_vec());
+
+        let reloaded = Sessions::new(Some(&settings(16, Some(60))), Some(&previous)).unwrap();
+        assert_eq!(reloaded.storage.get(b"id"), Some(b"session".to_vec()));
+        assert_eq!(reloaded.tickets.map(|x| x.rotation), Some(60));
+
+        let resized = Sessions::new(Some(&settings(32, None)), Some(&previous)).unwrap();
+        assert_eq!(resized.storage.get(b"id"), None);
+    }
+
+    #[test]
+    fn invalid_settings() {
+        assert!(Sessions::new(Some(&settings(16, Some(0))), None).is_err());
+
+        let missing = settings::TlsSessions {
+            ticket_key: Some("/nonexistent/ticket.key".into()),
+            ..settings(16, None)
+        };
+        assert!(Sessions::new(Some(&missing), None).is_err());
+    }
+}
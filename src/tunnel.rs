@@ -1,29 +1,53 @@
 use async_trait::async_trait;
 
-use tokio::net::TcpStream;
-use tokio::io::copy_bidirectional;
+use tokio::io::{copy_bidirectional, AsyncWriteExt};
 
 use std::error::Error;
 
+use crate::balancer::Balancer;
 use crate::handler::{Handler, Context};
 use crate::io::ProxyStream;
+use crate::proxy_protocol::ProxyHeader;
+use crate::settings::ProxyProtocolVersion;
+use crate::starttls::skip_greeting;
+use crate::tls::TlsClient;
 
 pub struct TunnelHandler {
-    target: String
+    targets: Balancer,
+    proxy_protocol: Option<ProxyProtocolVersion>,
+    tls: Option<TlsClient>
 }
 
 impl TunnelHandler {
-    pub fn new(target: String) -> Self {
+    pub fn new(targets: Balancer, proxy_protocol: Option<ProxyProtocolVersion>, tls: Option<TlsClient>) -> Self {
         Self {
-            target
+            targets,
+            proxy_protocol,
+            tls
         }
     }
 }
 
 #[async_trait]
 impl Handler for TunnelHandler {
-    async fn handle(&self, mut inbound: ProxyStream, _: Context) -> Result<(), Box<dyn Error>> {
-        let mut outbound = TcpStream::connect(&self.target).await?;
+    async fn handle(&self, mut inbound: ProxyStream, ctx: Context) -> Result<(), Box<dyn Error>> {
+        let (mut outbound, host, _lease) = self.targets.connect(ctx.addr.map(|x| x.ip())).await?;
+        if let Some(version) = self.proxy_protocol {
+            outbound.write_all(&ProxyHeader::from_context(&ctx).encode(version)).await?;
+        }
+
+        // The PROXY protocol header precedes the handshake
+        let mut outbound = match &self.tls {
+            Some(tls) => ProxyStream::new_dynamic(Box::pin(tls.connect(outbound, &host).await?)),
+            None => outbound
+        };
+
+        // The client was greeted before upgrading, the greeting of the backend would answer its next command
+        if let Some(protocol) = ctx.starttls {
+            let rest = skip_greeting(&mut outbound, protocol).await?;
+            inbound.write_all(&rest).await?;
+        }
+
         let r = copy_bidirectional(&mut inbound, &mut outbound).await;
         if let Err(e) = r {
             println!("Failed to transfer; error={}", e);